@@ -1,4 +1,5 @@
 import { onCleanup } from "solid-js";
+import type { Friend } from "meal-core";
 import { Group, Message } from "./database/schema";
 
 export const BROADCAST_NAME = "meal";
@@ -14,6 +15,13 @@ export type BroadcastMessage =
       groupId: string;
       message: Message;
     }
+  | {
+      /** Ephemeral and not stored in the database */
+      type: "Typing";
+      groupId: string;
+      friend: Friend;
+      typing: boolean;
+    }
   | {
       type: "Wipe";
     };
@@ -226,15 +226,12 @@ const handler = {
       getConfiguration(),
     ]);
 
-    //TODO fix invite storage/memory leak from creating and adding new key packages without removing them
-    const invite_payload = client.create_invite(
-      configuration.defaultUser?.name
-    );
+    const { payload } = client.create_invite(configuration.defaultUser?.name);
 
     // Persisting the client does not block us from responding
     void updateClient(client);
 
-    const inviteUrl = new URL(`/join/${invite_payload}`, location.origin);
+    const inviteUrl = new URL(`/join/${payload}`, location.origin);
     return inviteUrl.href;
   },
 
@@ -251,7 +248,7 @@ const handler = {
   async createGroup(friend: Friend, name: string) {
     const client = await getClient;
     // const { client: newClient, group_id } = create_group(client);
-    const groupId = client.create_group();
+    const groupId = client.create_group(name);
     void updateClient(client);
     const group: Group = {
       id: groupId,
@@ -268,14 +265,23 @@ const handler = {
   //TODO there might be overhead in passing the key package between contexts
   async inviteToGroup(groupId: string, keyPackage: DecodedPackage) {
     const client = await getClient;
-    const welcomePackage = client.invite(groupId, keyPackage);
+    // The existing members need the commit to move to the epoch the new friend joins in
+    const members = client.group_members(groupId);
+    const { welcome, commit } = client.invite(groupId, keyPackage);
     // Persist client change even if the buffer is shared
     void updateClient(client);
 
-    assertNotShared(welcomePackage);
-    const response = await postMessage(keyPackage.friend.id, welcomePackage);
-    if (response.status !== 201)
-      throw new Error(`Unexpected status code response ${response.status}`);
+    assertNotShared(welcome);
+    const responses = [postMessage(keyPackage.friend.id, welcome)];
+    if (commit) {
+      assertNotShared(commit);
+      responses.push(...members.map((member) => postMessage(member.id, commit)));
+    }
+
+    for (const response of await Promise.all(responses)) {
+      if (response.status !== 201)
+        throw new Error(`Unexpected status code response ${response.status}`);
+    }
   },
 
   async sendMessage(request: SendMessageRequest) {
@@ -299,7 +305,7 @@ const handler = {
 
     const client = await getClient;
     console.debug("[Serviceworker] Packing message");
-    const body = client.send_message(request.groupId, {
+    const { payload: body } = client.send_message(request.groupId, {
       sent_at: request.sentAt.toISOString(),
       text: request.text,
     });
@@ -311,6 +317,13 @@ const handler = {
     console.debug("[Serviceworker] Message sent", response.status);
   },
 
+  async sendTyping(request: { friendId: string; groupId: string; typing: boolean }) {
+    const client = await getClient;
+    const body = client.send_typing(request.groupId, request.typing);
+    assertNotShared(body);
+    await postMessage(request.friendId, body);
+  },
+
   async wipe() {
     // Wipe client
     await Promise.all([
@@ -328,26 +341,28 @@ const handler = {
 
   async receiveMessage(data: Uint8Array) {
     const client = await getClient;
-    const message = client.process_message(data);
-    console.debug("Processed message", message.type);
+    const messages = client.process_message(data);
     void updateClient(client);
-    switch (message.type) {
-      case "Welcome": {
-        console.debug("Processed welcome", message);
-        const configuration = await getConfiguration();
-        const group: Group = {
-          id: message.group_id,
-          friend: message.friend,
-          messages: [],
-          // TODO load user identity that theuser chose to be associated with the key package
-          user: configuration.defaultUser,
-        };
-        await insertGroup(group);
-        broadcastMessage({ type: "Group created", group });
-        return;
-      }
-      case "Private":
-        {
+    for (const message of messages) {
+      console.debug("Processed message", message.type);
+      switch (message.type) {
+        case "Welcome": {
+          console.debug("Processed welcome", message);
+          const configuration = await getConfiguration();
+          const group: Group = {
+            id: message.group_id,
+            friend: message.friend,
+            messages: [],
+            // Appear with the name the invite was created with
+            user: message.invite_label
+              ? { name: message.invite_label }
+              : configuration.defaultUser,
+          };
+          await insertGroup(group);
+          broadcastMessage({ type: "Group created", group });
+          break;
+        }
+        case "Private": {
           console.debug("Processed private message", message);
           const messageEntry: IncomingMessage = {
             type: "incoming",
@@ -364,8 +379,29 @@ const handler = {
             groupId: message.group_id,
             message: messageEntry,
           });
+          break;
         }
-        return;
+        case "Typing":
+          // Only shown while it is relevant so it is not stored with the messages
+          broadcastMessage({
+            type: "Typing",
+            groupId: message.group_id,
+            friend: message.sender,
+            typing: message.typing,
+          });
+          break;
+        case "Acknowledgement": {
+          // The delivery receipt is encrypted for the group and covers the messages of every sender in it
+          assertNotShared(message.payload);
+          for (const member of client.group_members(message.group_id)) {
+            void postMessage(member.id, message.payload);
+          }
+          break;
+        }
+        case "Failed":
+          console.error("Failed to process message", message.index, message.error);
+          break;
+      }
     }
   },
 };
@@ -1,8 +1,8 @@
 mod provider;
-mod v2;
+pub mod v2;
 
 use core::str;
-use std::{collections::HashMap, vec::IntoIter};
+use std::{collections::HashMap, str::Utf8Error, vec::IntoIter};
 
 use base64::prelude::*;
 use nanoid::nanoid;
@@ -64,31 +64,354 @@ pub struct MessageContent {
     text: String,
 }
 
+/// A file encrypted with its own key whose ciphertext is transferred separately from the message announcing it
+#[derive(Tsify, serde::Serialize, serde::Deserialize)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+pub struct Attachment {
+    pub mime_type: String,
+    /// The size of the file in bytes before encryption
+    pub size: u64,
+    /// The size of the chunks the file was encrypted in before encryption
+    pub chunk_size: u32,
+    #[serde(with = "serde_bytes")]
+    #[tsify(type = "Uint8Array")]
+    pub key: Vec<u8>,
+    /// The hash of the ciphertext
+    #[serde(with = "serde_bytes")]
+    #[tsify(type = "Uint8Array")]
+    pub hash: Vec<u8>,
+    /// Where to download the ciphertext from. Set by the app after uploading it.
+    pub location: Option<String>,
+}
+
+/// A message to send to a group
+#[derive(Tsify, serde::Deserialize)]
+#[tsify(from_wasm_abi)]
+pub struct OutgoingMessage {
+    /// The time the message is sent at
+    #[serde(with = "time::serde::iso8601")]
+    #[tsify(type = "string")]
+    pub sent_at: OffsetDateTime,
+    pub content: Content,
+}
+
+/// The content of a message to send to a group.
+/// Messages are referred to by the id they were received or sent with.
+#[derive(Tsify, serde::Deserialize)]
+#[serde(tag = "type")]
+pub enum Content {
+    Text {
+        text: String,
+    },
+    Reply {
+        reply_to: String,
+        text: String,
+    },
+    /// Replaces the text of one of our messages
+    Edit {
+        edited: String,
+        text: String,
+    },
+    /// Asks the other members to no longer show one of our messages
+    Delete {
+        deleted: String,
+    },
+    Reaction {
+        reacted_to: String,
+        emoji: String,
+        /// If a previous reaction with the emoji is taken back
+        removed: bool,
+    },
+    Attachment {
+        attachment: Attachment,
+        /// Shown together with the attachment. Can be empty.
+        text: String,
+    },
+}
+
 #[derive(serde::Serialize, Tsify)]
 #[tsify(into_wasm_abi)]
 #[serde(tag = "type")]
 pub enum Message {
     Private {
         group_id: String,
+        /// The member that sent the message as authenticated by their credential
+        sender: Friend,
+        /// Is `None` for messages from clients that only send plain text
+        id: Option<String>,
+        content: MessageContent,
+    },
+    /// A text message answering an earlier message
+    Reply {
+        group_id: String,
+        sender: Friend,
+        id: String,
+        reply_to: String,
+        content: MessageContent,
+    },
+    /// The sender changed the text of one of their messages
+    Edit {
+        group_id: String,
+        sender: Friend,
+        id: String,
+        edited: String,
         content: MessageContent,
     },
+    /// The sender deleted one of their messages
+    Delete {
+        group_id: String,
+        sender: Friend,
+        id: String,
+        deleted: String,
+        #[serde(with = "time::serde::iso8601")]
+        #[tsify(type = "string")]
+        sent_at: OffsetDateTime,
+    },
+    Reaction {
+        group_id: String,
+        sender: Friend,
+        id: String,
+        reacted_to: String,
+        emoji: String,
+        /// If the sender took back their reaction with the emoji
+        removed: bool,
+        #[serde(with = "time::serde::iso8601")]
+        #[tsify(type = "string")]
+        sent_at: OffsetDateTime,
+    },
     Welcome {
         group_id: String,
         friend: Friend,
+        /// The id of the invite the friend used to add us to the group
+        invite_id: Option<String>,
+        /// The user name the invite was created with
+        invite_label: Option<String>,
+        /// The group we asked the friend to recover after losing our state of it
+        replaces: Option<String>,
+    },
+    /// A commit from another member was merged and the group moved to a new epoch
+    Commit {
+        group_id: String,
+        /// The friends that joined the group with the commit
+        added: Vec<Friend>,
+        /// The friends that left or were removed from the group with the commit
+        removed: Vec<Friend>,
+        /// The friends that rotated their keys with the commit
+        updated: Vec<Friend>,
+        /// The friends whose signature key changed with the commit.
+        /// Their safety number changed and they are no longer verified.
+        key_changed: Vec<Friend>,
+    },
+    /// A friend wants to leave the group.
+    /// Their removal needs to be committed by another member with `commit_pending_proposals`.
+    Left { group_id: String, friend: Friend },
+    /// We were removed from the group and the group was deleted
+    Removed {
+        group_id: String,
+        /// The friend that committed our removal
+        by: Friend,
+    },
+    /// Another member proposed a change to the group that takes effect with the next commit
+    Proposal {
+        group_id: String,
+        sender: Friend,
+        change: ProposedChange,
+    },
+    /// The sender received our messages with the ids
+    Delivered {
+        group_id: String,
+        sender: Friend,
+        ids: Vec<String>,
+    },
+    /// The sender read our messages with the ids
+    Read {
+        group_id: String,
+        sender: Friend,
+        ids: Vec<String>,
+    },
+    /// The sender shared a file. The text of the content is shown with it.
+    Attachment {
+        group_id: String,
+        sender: Friend,
+        id: String,
+        attachment: Attachment,
+        content: MessageContent,
+    },
+    /// The sender started or stopped typing.
+    /// Ephemeral and only meaningful right now, so it should not be stored with the messages of the group.
+    Typing {
+        group_id: String,
+        sender: Friend,
+        typing: bool,
+    },
+    /// Tells the members of the group that we received the messages of the batch.
+    /// The payload has to be sent to the group like any other message.
+    Acknowledgement {
+        group_id: String,
+        #[serde(with = "serde_bytes")]
+        #[tsify(type = "Uint8Array")]
+        payload: Vec<u8>,
+    },
+    /// Messages of the group failed to be processed repeatedly.
+    /// Our state of the group is probably lost or out of sync with the other members. The app can ask the friend to
+    /// recover the group with `request_recovery`.
+    Desynced { group_id: String, failures: u32 },
+    /// The friend lost their state of a group and asks to be added to a new group that replaces it.
+    /// Answered with `recover_group`.
+    RecoveryRequested {
+        key_package: DecodedPackage,
+        /// The groups we share with the friend. Is empty if the friend lost their identity as well and only the user
+        /// can tell which group to recover.
+        groups: Vec<String>,
+        /// The friend asks with a signature key we have not seen them use before. Anyone can ask in the name of a
+        /// friend, so the app should confirm with the friend out of band before recovering the group.
+        unknown_key: bool,
+    },
+    /// The message is from an epoch the group has not reached yet.
+    /// It is kept and processed once the commit moving the group to its epoch arrives.
+    Pending { group_id: String },
+    /// Messages waiting for their epoch were dropped as too many arrived before the commit they wait for.
+    /// They count as failed messages, so enough of them are followed by [`Message::Desynced`].
+    Expired { group_id: String, count: u32 },
+    /// The message was processed before and was delivered again
+    Duplicate {
+        group_id: String,
+        /// Is `None` if the message was recognized before decrypting it
+        id: Option<String>,
+    },
+    /// A message from a blocked friend was dropped
+    Blocked { group_id: String, sender: Friend },
+    /// A message in a batch could not be processed
+    Failed {
+        /// The position of the message in the batch
+        index: usize,
+        error: String,
     },
 }
 
-fn encode_application_id(id: &str, name: &Option<String>) -> Extensions {
+/// A change to a group proposed by a member
+#[derive(serde::Serialize, Tsify)]
+#[serde(tag = "type")]
+pub enum ProposedChange {
+    Add {
+        friend: Friend,
+    },
+    Remove {
+        friend: Friend,
+    },
+    /// The sender wants to rotate their keys
+    Update,
+    /// Changes to the group that are not relevant to the user like extensions or pre-shared keys
+    Other,
+}
+
+fn application_id(id: &str, name: &Option<String>) -> String {
     let mut id = id.to_owned();
     if let Some(name) = name {
         id.push_str(name);
     }
 
+    id
+}
+
+fn encode_application_id(id: &str, name: &Option<String>) -> Extensions {
+    let id = application_id(id, name);
     Extensions::single(Extension::ApplicationId(ApplicationIdExtension::new(
         id.as_bytes(),
     )))
 }
 
+/// OpenMLS only allows unknown extensions in leaf nodes so the application id can not be used there.
+/// Instead the same content is carried in an extension from the private use range.
+/// Details: https://www.rfc-editor.org/rfc/rfc9420.html#section-17.3
+const LEAF_NODE_APPLICATION_ID: u16 = 0xF000;
+
+fn encode_leaf_node_application_id(id: &str, name: &Option<String>) -> Extensions {
+    let id = application_id(id, name);
+    Extensions::single(Extension::Unknown(
+        LEAF_NODE_APPLICATION_ID,
+        UnknownExtension(id.into_bytes()),
+    ))
+}
+
+/// Leaf nodes have to list the extensions they use in their capabilities.
+/// This includes the last resort extension of key packages that can be used more than once.
+fn leaf_node_capabilities() -> Capabilities {
+    Capabilities::new(
+        None,
+        None,
+        Some(&[
+            ExtensionType::Unknown(LEAF_NODE_APPLICATION_ID),
+            ExtensionType::LastResort,
+        ]),
+        None,
+        None,
+    )
+}
+
+fn leaf_node_application_id(leaf_node: &LeafNode) -> Option<&[u8]> {
+    leaf_node
+        .extensions()
+        .iter()
+        .find_map(|extension| match extension {
+            Extension::Unknown(LEAF_NODE_APPLICATION_ID, UnknownExtension(id)) => {
+                Some(id.as_slice())
+            }
+            _ => None,
+        })
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReadCredentialError {
+    #[error("Credential is not a basic credential: {0}")]
+    NotBasicCredential(#[from] BasicCredentialError),
+    #[error("Credential identity is not a valid client id: {0}")]
+    InvalidIdentity(#[from] Utf8Error),
+    #[error("Leaf node claims id {claimed} which does not match the credential identity {actual}")]
+    ApplicationIdMismatch { claimed: String, actual: String },
+}
+
+/// Reads the friend from the credential of a group member.
+/// The name is not part of the credential and can not be retrieved this way.
+fn friend_from_credential(credential: Credential) -> Result<Friend, ReadCredentialError> {
+    let credential = BasicCredential::try_from(credential)?;
+    let id = str::from_utf8(credential.identity())?;
+    Ok(Friend {
+        id: id.to_owned(),
+        name: None,
+    })
+}
+
+/// Splits the application id created with [`encode_application_id`] into the client id and the optional name
+fn decode_application_id(application_id: &[u8]) -> Result<(&str, Option<&str>), Utf8Error> {
+    let id = str::from_utf8(application_id)?;
+    if id.len() > ID_LENGTH {
+        let (id, name) = id.split_at(ID_LENGTH);
+        Ok((id, Some(name)))
+    } else {
+        Ok((id, None))
+    }
+}
+
+/// Reads the friend from the leaf node of a group member.
+/// The id is read from the credential and the name from the application id extension if the member included one.
+fn friend_from_leaf_node(leaf_node: &LeafNode) -> Result<Friend, ReadCredentialError> {
+    let mut friend = friend_from_credential(leaf_node.credential().clone())?;
+    if let Some(application_id) = leaf_node_application_id(leaf_node) {
+        let (id, name) = decode_application_id(application_id)?;
+        if id != friend.id {
+            return Err(ReadCredentialError::ApplicationIdMismatch {
+                claimed: id.to_owned(),
+                actual: friend.id,
+            });
+        }
+
+        friend.name = name.map(str::to_owned);
+    }
+
+    Ok(friend)
+}
+
 /// Defines a message sent on the application layer.
 /// This gets transported using MLS but is otherwise independent of the protocol.
 #[derive(Serialize, Deserialize)]
@@ -136,7 +459,7 @@ impl Client {
         };
 
         let user = User {
-            name: name.into(),
+            name,
             credential,
             signature_key: signature_keys,
         };
@@ -203,7 +526,7 @@ impl Client {
 
         let group_id = group.group_id();
 
-        if self.groups.contains_key(&group_id) {
+        if self.groups.contains_key(group_id) {
             todo!("Group id collision that should not happen if group id is random");
         }
 
@@ -248,9 +571,7 @@ impl Client {
         //TODO the introduction has to be sent to all group members when we support multi user groups
         // Batch send messages
         //TODO test without vector and U8 slice variant
-        let mut vector = Vec::new();
-        vector.push(welcome);
-        vector.push(message);
+        let vector = vec![welcome, message];
         TlsSliceU16(&vector).tls_serialize_detached().unwrap()
     }
 
@@ -283,6 +604,12 @@ impl Client {
             todo!("Message processing error");
         };
 
+        // The credential is controlled by the sender, so one that can not be read shows an unknown sender
+        let sender =
+            friend_from_credential(message.credential().clone()).unwrap_or_else(|_| Friend {
+                id: String::new(),
+                name: None,
+            });
         let ProcessedMessageContent::ApplicationMessage(content) = message.into_content() else {
             todo!("Handle processed message content");
         };
@@ -293,6 +620,8 @@ impl Client {
 
         Message::Private {
             group_id: js_group_id,
+            sender,
+            id: None,
             content,
         }
     }
@@ -336,6 +665,9 @@ impl Client {
         Message::Welcome {
             friend: Friend { id, name },
             group_id: js_group_id,
+            invite_id: None,
+            invite_label: None,
+            replaces: None,
         }
     }
 
@@ -129,21 +129,21 @@ impl LocalStorage {
         &self,
         key: &str,
     ) -> Result<Option<T>, GetItemError> {
-        let encoded = self.0.get_item(&key).map_err(GetItemError::GetItemError)?;
+        let encoded = self.0.get_item(key).map_err(GetItemError::GetItemError)?;
 
         let result = encoded.map(|item| destringalize::<T>(&item)).transpose()?;
         Ok(result)
     }
 
     fn remove_item(&self, key: &str) -> Result<(), RemoveItemError> {
-        self.0.remove_item(&key).map_err(RemoveItemError)
+        self.0.remove_item(key).map_err(RemoveItemError)
     }
 
     fn set_item(&self, key: &str, value: &impl Stringalize) -> Result<(), SetItemError> {
         let value = value.stringalize()?;
 
         self.0
-            .set_item(&key, &value)
+            .set_item(key, &value)
             .map_err(SetItemError::SetItemError)
     }
 }
@@ -191,6 +191,7 @@ pub(crate) enum SetItemError {
 }
 
 #[derive(Debug, thiserror::Error)]
+#[allow(clippy::enum_variant_names)]
 pub(crate) enum LocalStorageError {
     #[error("Error stringalizing: {0}")]
     StringalizeError(#[from] StringalizeError),
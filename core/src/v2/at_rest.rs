@@ -0,0 +1,411 @@
+//! Encryption of the serialized client at rest.
+//! The client is encrypted with a random storage key which itself is encrypted with a key derived from the passphrase
+//! of the user. Changing the passphrase only encrypts the storage key again while rotating replaces the storage key, so
+//! copies of the state that were encrypted before can not be decrypted with a leaked storage key.
+//!
+//! Deriving the key from the passphrase is slow on purpose. The storage key is kept in memory once the client is
+//! unlocked, so the client can be saved after every operation without deriving the key again.
+//!
+//! Layout: `[MAGIC, SealedClient...]`
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use openmls_traits::{crypto::OpenMlsCrypto, random::OpenMlsRand, types::CryptoError};
+use serde::{Deserialize, Serialize};
+
+use crate::CIPHERSUITE;
+
+/// Tells encrypted clients apart from clients serialized without encryption.
+/// Clients serialized without encryption start with their format prefix or the length of their id instead.
+const MAGIC: &[u8] = b"meal-sealed";
+
+const SALT_LENGTH: usize = 16;
+
+/// Upper bounds for the stored key derivation parameters.
+/// A modified client could otherwise make us allocate terabytes of memory or derive the key for hours. They have to stay
+/// above the defaults that clients are encrypted with.
+const MAX_MEMORY_KIB: u32 = 256 * 1024;
+const MAX_ITERATIONS: u32 = 16;
+const MAX_PARALLELISM: u32 = 4;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StorageEncryptionError {
+    #[error("Error generating storage key")]
+    Random,
+    #[error("Error deriving key from passphrase: {0}")]
+    Derive(#[from] argon2::Error),
+    #[error("Error encrypting client: {0}")]
+    Encrypt(CryptoError),
+    #[error("Serialized client is not encrypted")]
+    NotEncrypted,
+    #[error("Error reading encrypted client: {0}")]
+    Deserialize(#[from] postcard::Error),
+    #[error("Passphrase is wrong")]
+    WrongPassphrase,
+    #[error("Encrypted client could not be decrypted. It might have been modified")]
+    Decrypt,
+}
+
+/// The parameters of the key derivation.
+/// Stored with the client so they can be raised for new clients without breaking existing ones.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct KdfParams {
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+}
+
+impl KdfParams {
+    fn clamped(self) -> Self {
+        Self {
+            memory_kib: self.memory_kib.min(MAX_MEMORY_KIB),
+            iterations: self.iterations.min(MAX_ITERATIONS),
+            parallelism: self.parallelism.min(MAX_PARALLELISM),
+        }
+    }
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SealedClient {
+    salt: Vec<u8>,
+    params: KdfParams,
+    wrap_nonce: Vec<u8>,
+    /// The storage key encrypted with the key derived from the passphrase
+    wrapped_key: Vec<u8>,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+/// The unlocked storage key together with how it is stored
+pub(super) struct StorageKey {
+    key: Vec<u8>,
+    salt: Vec<u8>,
+    params: KdfParams,
+    wrap_nonce: Vec<u8>,
+    wrapped_key: Vec<u8>,
+    /// Checks the passphrase when saving without deriving the key again. Never stored.
+    passphrase_hash: Vec<u8>,
+}
+
+fn derive_key(
+    passphrase: &str,
+    salt: &[u8],
+    params: KdfParams,
+) -> Result<Vec<u8>, StorageEncryptionError> {
+    let params = Params::new(
+        params.memory_kib,
+        params.iterations,
+        params.parallelism,
+        Some(CIPHERSUITE.aead_key_length()),
+    )?;
+    let mut key = vec![0; CIPHERSUITE.aead_key_length()];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params).hash_password_into(
+        passphrase.as_bytes(),
+        salt,
+        &mut key,
+    )?;
+    Ok(key)
+}
+
+fn hash_passphrase(
+    crypto: &impl OpenMlsCrypto,
+    passphrase: &str,
+    salt: &[u8],
+) -> Result<Vec<u8>, StorageEncryptionError> {
+    crypto
+        .hash(
+            CIPHERSUITE.hash_algorithm(),
+            &[salt, passphrase.as_bytes()].concat(),
+        )
+        .map_err(StorageEncryptionError::Encrypt)
+}
+
+fn random(rand: &impl OpenMlsRand, length: usize) -> Result<Vec<u8>, StorageEncryptionError> {
+    rand.random_vec(length)
+        .map_err(|_| StorageEncryptionError::Random)
+}
+
+impl StorageKey {
+    /// Generates a new random storage key protected by the passphrase
+    pub(super) fn new(
+        provider: &(impl OpenMlsCrypto + OpenMlsRand),
+        passphrase: &str,
+    ) -> Result<Self, StorageEncryptionError> {
+        let key = random(provider, CIPHERSUITE.aead_key_length())?;
+        Self::wrap(provider, key, passphrase)
+    }
+
+    /// Protects the storage key with a key derived from the passphrase and a new salt
+    fn wrap(
+        provider: &(impl OpenMlsCrypto + OpenMlsRand),
+        key: Vec<u8>,
+        passphrase: &str,
+    ) -> Result<Self, StorageEncryptionError> {
+        let salt = random(provider, SALT_LENGTH)?;
+        let params = KdfParams::default();
+        let wrapping_key = derive_key(passphrase, &salt, params)?;
+        let wrap_nonce = random(provider, CIPHERSUITE.aead_nonce_length())?;
+        let wrapped_key = provider
+            .aead_encrypt(
+                CIPHERSUITE.aead_algorithm(),
+                &wrapping_key,
+                &key,
+                &wrap_nonce,
+                MAGIC,
+            )
+            .map_err(StorageEncryptionError::Encrypt)?;
+        let passphrase_hash = hash_passphrase(provider, passphrase, &salt)?;
+
+        Ok(Self {
+            key,
+            salt,
+            params,
+            wrap_nonce,
+            wrapped_key,
+            passphrase_hash,
+        })
+    }
+
+    pub(super) fn matches(
+        &self,
+        crypto: &impl OpenMlsCrypto,
+        passphrase: &str,
+    ) -> Result<bool, StorageEncryptionError> {
+        Ok(hash_passphrase(crypto, passphrase, &self.salt)? == self.passphrase_hash)
+    }
+
+    /// Keeps the storage key but protects it with the new passphrase
+    pub(super) fn change_passphrase(
+        &self,
+        provider: &(impl OpenMlsCrypto + OpenMlsRand),
+        passphrase: &str,
+    ) -> Result<Self, StorageEncryptionError> {
+        Self::wrap(provider, self.key.clone(), passphrase)
+    }
+
+    /// Encrypts the serialized client with a new nonce
+    pub(super) fn seal(
+        &self,
+        provider: &(impl OpenMlsCrypto + OpenMlsRand),
+        serialized: &[u8],
+    ) -> Result<Vec<u8>, StorageEncryptionError> {
+        let nonce = random(provider, CIPHERSUITE.aead_nonce_length())?;
+        let ciphertext = provider
+            .aead_encrypt(
+                CIPHERSUITE.aead_algorithm(),
+                &self.key,
+                serialized,
+                &nonce,
+                MAGIC,
+            )
+            .map_err(StorageEncryptionError::Encrypt)?;
+
+        let sealed = SealedClient {
+            salt: self.salt.clone(),
+            params: self.params,
+            wrap_nonce: self.wrap_nonce.clone(),
+            wrapped_key: self.wrapped_key.clone(),
+            nonce,
+            ciphertext,
+        };
+        Ok(postcard::to_extend(&sealed, MAGIC.to_vec())?)
+    }
+
+    /// Decrypts the serialized client.
+    /// Returns the serialized client and the storage key to encrypt it again when saving.
+    pub(super) fn open(
+        crypto: &impl OpenMlsCrypto,
+        bytes: &[u8],
+        passphrase: &str,
+    ) -> Result<(Vec<u8>, Self), StorageEncryptionError> {
+        let sealed = bytes
+            .strip_prefix(MAGIC)
+            .ok_or(StorageEncryptionError::NotEncrypted)?;
+        let sealed: SealedClient = postcard::from_bytes(sealed)?;
+        let params = sealed.params.clamped();
+
+        let wrapping_key = derive_key(passphrase, &sealed.salt, params)?;
+        // The storage key is authenticated so a wrong passphrase fails here
+        let key = crypto
+            .aead_decrypt(
+                CIPHERSUITE.aead_algorithm(),
+                &wrapping_key,
+                &sealed.wrapped_key,
+                &sealed.wrap_nonce,
+                MAGIC,
+            )
+            .map_err(|_| StorageEncryptionError::WrongPassphrase)?;
+
+        let serialized = crypto
+            .aead_decrypt(
+                CIPHERSUITE.aead_algorithm(),
+                &key,
+                &sealed.ciphertext,
+                &sealed.nonce,
+                MAGIC,
+            )
+            .map_err(|_| StorageEncryptionError::Decrypt)?;
+
+        let passphrase_hash = hash_passphrase(crypto, passphrase, &sealed.salt)?;
+        let storage_key = Self {
+            key,
+            salt: sealed.salt,
+            params,
+            wrap_nonce: sealed.wrap_nonce,
+            wrapped_key: sealed.wrapped_key,
+            passphrase_hash,
+        };
+
+        Ok((serialized, storage_key))
+    }
+}
+
+/// If the serialized client was encrypted with a passphrase
+pub(super) fn is_encrypted(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+#[cfg(test)]
+mod tests {
+    use openmls_rust_crypto::RustCrypto;
+
+    use super::*;
+
+    const STATE: &[u8] = b"serialized client";
+
+    fn seal(passphrase: &str) -> (RustCrypto, StorageKey, Vec<u8>) {
+        let crypto = RustCrypto::default();
+        let storage_key = StorageKey::new(&crypto, passphrase).unwrap();
+        let sealed = storage_key.seal(&crypto, STATE).unwrap();
+        (crypto, storage_key, sealed)
+    }
+
+    fn modify(sealed: &[u8], change: impl FnOnce(&mut SealedClient)) -> Vec<u8> {
+        let mut client: SealedClient = postcard::from_bytes(&sealed[MAGIC.len()..]).unwrap();
+        change(&mut client);
+        postcard::to_extend(&client, MAGIC.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let (crypto, storage_key, sealed) = seal("passphrase");
+        assert!(is_encrypted(&sealed));
+
+        let (serialized, opened) = StorageKey::open(&crypto, &sealed, "passphrase").unwrap();
+        assert_eq!(serialized, STATE);
+        assert_eq!(opened.key, storage_key.key);
+        assert!(opened.matches(&crypto, "passphrase").unwrap());
+        assert!(!opened.matches(&crypto, "other").unwrap());
+    }
+
+    #[test]
+    fn rejects_wrong_passphrase() {
+        let (crypto, _, sealed) = seal("passphrase");
+        assert!(matches!(
+            StorageKey::open(&crypto, &sealed, "other"),
+            Err(StorageEncryptionError::WrongPassphrase)
+        ));
+    }
+
+    #[test]
+    fn rejects_unencrypted_client() {
+        let crypto = RustCrypto::default();
+        assert!(!is_encrypted(STATE));
+        assert!(matches!(
+            StorageKey::open(&crypto, STATE, "passphrase"),
+            Err(StorageEncryptionError::NotEncrypted)
+        ));
+    }
+
+    #[test]
+    fn rejects_tampered_ciphertext() {
+        let (crypto, _, sealed) = seal("passphrase");
+        let tampered = modify(&sealed, |client| client.ciphertext[0] ^= 1);
+        assert!(matches!(
+            StorageKey::open(&crypto, &tampered, "passphrase"),
+            Err(StorageEncryptionError::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn changed_passphrase_replaces_old_one() {
+        let (crypto, storage_key, _) = seal("old");
+        let changed = storage_key.change_passphrase(&crypto, "new").unwrap();
+        let sealed = changed.seal(&crypto, STATE).unwrap();
+
+        assert!(matches!(
+            StorageKey::open(&crypto, &sealed, "old"),
+            Err(StorageEncryptionError::WrongPassphrase)
+        ));
+        let (serialized, opened) = StorageKey::open(&crypto, &sealed, "new").unwrap();
+        assert_eq!(serialized, STATE);
+        // Only the protection of the storage key changed
+        assert_eq!(opened.key, storage_key.key);
+    }
+
+    #[test]
+    fn rotated_key_replaces_old_one() {
+        let (crypto, storage_key, _) = seal("passphrase");
+        let rotated = StorageKey::new(&crypto, "passphrase").unwrap();
+        assert_ne!(rotated.key, storage_key.key);
+
+        // A leaked previous key does not decrypt what is saved after rotating
+        let sealed = rotated.seal(&crypto, STATE).unwrap();
+        let client: SealedClient = postcard::from_bytes(&sealed[MAGIC.len()..]).unwrap();
+        assert!(
+            crypto
+                .aead_decrypt(
+                    CIPHERSUITE.aead_algorithm(),
+                    &storage_key.key,
+                    &client.ciphertext,
+                    &client.nonce,
+                    MAGIC,
+                )
+                .is_err()
+        );
+        assert_eq!(
+            StorageKey::open(&crypto, &sealed, "passphrase").unwrap().0,
+            STATE
+        );
+    }
+
+    #[test]
+    fn clamps_stored_key_derivation_parameters() {
+        let (crypto, _, sealed) = seal("passphrase");
+        let modified = modify(&sealed, |client| {
+            client.params = KdfParams {
+                memory_kib: u32::MAX,
+                iterations: 1,
+                parallelism: 1,
+            };
+        });
+
+        // Derives a different key within the bounds instead of allocating terabytes
+        assert!(matches!(
+            StorageKey::open(&crypto, &modified, "passphrase"),
+            Err(StorageEncryptionError::WrongPassphrase)
+        ));
+        let clamped = KdfParams {
+            memory_kib: u32::MAX,
+            iterations: u32::MAX,
+            parallelism: u32::MAX,
+        }
+        .clamped();
+        assert_eq!(clamped.memory_kib, MAX_MEMORY_KIB);
+        assert_eq!(clamped.iterations, MAX_ITERATIONS);
+        assert_eq!(clamped.parallelism, MAX_PARALLELISM);
+        let defaults = KdfParams::default();
+        assert!(defaults.memory_kib <= MAX_MEMORY_KIB);
+        assert!(defaults.iterations <= MAX_ITERATIONS);
+        assert!(defaults.parallelism <= MAX_PARALLELISM);
+    }
+}
@@ -0,0 +1,334 @@
+//! Encrypted attachments that travel separately from the MLS message announcing them.
+//! Every file is encrypted with its own random key in chunks, so large files can be decrypted and verified piece by
+//! piece while downloading. The key and the hash of the ciphertext are sent in the MLS message, which authenticates
+//! them, so whoever stores or forwards the ciphertext can neither read nor swap it.
+//!
+//! Each chunk is sealed with the AEAD of the ciphersuite. The nonce is the index of the chunk, which is safe as the key
+//! is never reused for another file. The authenticated data binds the chunk to its position and marks the last chunk to
+//! detect reordered and cut off files.
+
+use openmls_traits::{crypto::OpenMlsCrypto, random::OpenMlsRand, types::CryptoError};
+
+use crate::{Attachment, CIPHERSUITE};
+
+/// The size of the plaintext of each chunk except the last one
+const CHUNK_SIZE: u32 = 64 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum AttachmentError {
+    #[error("Error generating attachment key")]
+    Random,
+    #[error("Error encrypting attachment: {0}")]
+    Encrypt(CryptoError),
+    #[error("Error hashing attachment: {0}")]
+    Hash(CryptoError),
+    #[error("Attachment has an invalid chunk size of 0")]
+    InvalidChunkSize,
+    #[error("Attachment has {count} chunks but chunk {index} was requested")]
+    ChunkOutOfRange { index: u64, count: u64 },
+    #[error("Ciphertext of the attachment is {actual} bytes long but should be {expected} bytes")]
+    LengthMismatch { expected: u64, actual: u64 },
+    #[error("Ciphertext does not match the hash of the attachment")]
+    HashMismatch,
+    #[error("Chunk {0} of the attachment could not be decrypted. It might have been modified")]
+    Decrypt(u64),
+}
+
+fn tag_length() -> usize {
+    CIPHERSUITE.aead_algorithm().tag_size()
+}
+
+/// The number of chunks the file is split into. Empty files still have one empty chunk.
+fn chunk_count(size: u64, chunk_size: u32) -> u64 {
+    size.div_ceil(chunk_size.into()).max(1)
+}
+
+/// The length of the chunk in the ciphertext including the authentication tag
+fn sealed_chunk_length(attachment: &Attachment, index: u64) -> u64 {
+    let chunk_size = u64::from(attachment.chunk_size);
+    let plaintext = if index + 1 == chunk_count(attachment.size, attachment.chunk_size) {
+        attachment.size - index * chunk_size
+    } else {
+        chunk_size
+    };
+
+    plaintext + tag_length() as u64
+}
+
+fn nonce(index: u64) -> Vec<u8> {
+    let mut nonce = vec![0; CIPHERSUITE.aead_nonce_length()];
+    let start = nonce.len() - size_of::<u64>();
+    nonce[start..].copy_from_slice(&index.to_be_bytes());
+    nonce
+}
+
+fn chunk_aad(index: u64, is_last: bool) -> [u8; 9] {
+    let mut aad = [0; 9];
+    aad[..8].copy_from_slice(&index.to_be_bytes());
+    aad[8] = is_last.into();
+    aad
+}
+
+/// Encrypts the file with a new random key.
+/// Returns the ciphertext of all chunks one after another and the attachment to send to the group.
+pub(super) fn encrypt_attachment(
+    provider: &(impl OpenMlsCrypto + OpenMlsRand),
+    data: &[u8],
+    mime_type: String,
+) -> Result<(Vec<u8>, Attachment), AttachmentError> {
+    let key = provider
+        .random_vec(CIPHERSUITE.aead_key_length())
+        .map_err(|_| AttachmentError::Random)?;
+
+    // Slicing an empty file yields no chunk but it needs one to detect the file being cut off
+    let chunks: Vec<&[u8]> = if data.is_empty() {
+        vec![data]
+    } else {
+        data.chunks(CHUNK_SIZE as usize).collect()
+    };
+
+    let mut ciphertext = Vec::with_capacity(data.len() + chunks.len() * tag_length());
+    for (index, chunk) in chunks.iter().enumerate() {
+        let index = index as u64;
+        let is_last = index + 1 == chunks.len() as u64;
+        let sealed = provider
+            .aead_encrypt(
+                CIPHERSUITE.aead_algorithm(),
+                &key,
+                chunk,
+                &nonce(index),
+                &chunk_aad(index, is_last),
+            )
+            .map_err(AttachmentError::Encrypt)?;
+        ciphertext.extend(sealed);
+    }
+
+    let hash = provider
+        .hash(CIPHERSUITE.hash_algorithm(), &ciphertext)
+        .map_err(AttachmentError::Hash)?;
+
+    let attachment = Attachment {
+        mime_type,
+        size: data.len() as u64,
+        chunk_size: CHUNK_SIZE,
+        key,
+        hash,
+        location: None,
+    };
+
+    Ok((ciphertext, attachment))
+}
+
+/// Decrypts a single chunk, for example while the ciphertext is still downloading.
+/// The chunk is authenticated on its own but only checking the hash of the whole ciphertext proves it is the file that
+/// was sent.
+pub(super) fn decrypt_attachment_chunk(
+    crypto: &impl OpenMlsCrypto,
+    attachment: &Attachment,
+    index: u64,
+    chunk: &[u8],
+) -> Result<Vec<u8>, AttachmentError> {
+    if attachment.chunk_size == 0 {
+        return Err(AttachmentError::InvalidChunkSize);
+    }
+
+    let count = chunk_count(attachment.size, attachment.chunk_size);
+    if index >= count {
+        return Err(AttachmentError::ChunkOutOfRange { index, count });
+    }
+
+    let expected = sealed_chunk_length(attachment, index);
+    if chunk.len() as u64 != expected {
+        return Err(AttachmentError::LengthMismatch {
+            expected,
+            actual: chunk.len() as u64,
+        });
+    }
+
+    crypto
+        .aead_decrypt(
+            CIPHERSUITE.aead_algorithm(),
+            &attachment.key,
+            chunk,
+            &nonce(index),
+            &chunk_aad(index, index + 1 == count),
+        )
+        .map_err(|_| AttachmentError::Decrypt(index))
+}
+
+/// Verifies the hash of the ciphertext and decrypts all chunks
+pub(super) fn decrypt_attachment(
+    crypto: &impl OpenMlsCrypto,
+    attachment: &Attachment,
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, AttachmentError> {
+    if attachment.chunk_size == 0 {
+        return Err(AttachmentError::InvalidChunkSize);
+    }
+
+    let count = chunk_count(attachment.size, attachment.chunk_size);
+    let expected = count
+        .saturating_mul(tag_length() as u64)
+        .saturating_add(attachment.size);
+    if ciphertext.len() as u64 != expected {
+        return Err(AttachmentError::LengthMismatch {
+            expected,
+            actual: ciphertext.len() as u64,
+        });
+    }
+
+    let hash = crypto
+        .hash(CIPHERSUITE.hash_algorithm(), ciphertext)
+        .map_err(AttachmentError::Hash)?;
+    if hash != attachment.hash {
+        return Err(AttachmentError::HashMismatch);
+    }
+
+    let mut data = Vec::with_capacity(ciphertext.len());
+    let mut rest = ciphertext;
+    for index in 0..count {
+        let (chunk, remaining) = rest.split_at(sealed_chunk_length(attachment, index) as usize);
+        data.extend(decrypt_attachment_chunk(crypto, attachment, index, chunk)?);
+        rest = remaining;
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use openmls_rust_crypto::RustCrypto;
+
+    use super::*;
+
+    const MIME_TYPE: &str = "application/octet-stream";
+
+    fn data(size: usize) -> Vec<u8> {
+        (0..size).map(|index| index as u8).collect()
+    }
+
+    fn round_trip(size: usize) {
+        let crypto = RustCrypto::default();
+        let data = data(size);
+        let (ciphertext, attachment) =
+            encrypt_attachment(&crypto, &data, MIME_TYPE.to_owned()).unwrap();
+
+        assert_eq!(attachment.size, size as u64);
+        let count = chunk_count(attachment.size, attachment.chunk_size);
+        assert_eq!(
+            ciphertext.len() as u64,
+            attachment.size + count * tag_length() as u64
+        );
+        assert_eq!(
+            decrypt_attachment(&crypto, &attachment, &ciphertext).unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn empty_file() {
+        round_trip(0);
+    }
+
+    #[test]
+    fn exactly_one_chunk() {
+        round_trip(CHUNK_SIZE as usize);
+    }
+
+    #[test]
+    fn one_byte_more_than_a_chunk() {
+        round_trip(CHUNK_SIZE as usize + 1);
+    }
+
+    #[test]
+    fn chunks_match_whole_file() {
+        let crypto = RustCrypto::default();
+        let data = data(CHUNK_SIZE as usize * 2 + 100);
+        let (ciphertext, attachment) =
+            encrypt_attachment(&crypto, &data, MIME_TYPE.to_owned()).unwrap();
+
+        let mut decrypted = Vec::new();
+        let mut rest = ciphertext.as_slice();
+        for index in 0..chunk_count(attachment.size, attachment.chunk_size) {
+            let (chunk, remaining) =
+                rest.split_at(sealed_chunk_length(&attachment, index) as usize);
+            decrypted.extend(decrypt_attachment_chunk(&crypto, &attachment, index, chunk).unwrap());
+            rest = remaining;
+        }
+
+        assert!(rest.is_empty());
+        assert_eq!(decrypted, data);
+        assert_eq!(
+            decrypt_attachment(&crypto, &attachment, &ciphertext).unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn rejects_swapped_last_chunk() {
+        let crypto = RustCrypto::default();
+        let data = data(CHUNK_SIZE as usize * 2);
+        let (ciphertext, attachment) =
+            encrypt_attachment(&crypto, &data, MIME_TYPE.to_owned()).unwrap();
+        let (first, last) = ciphertext.split_at(sealed_chunk_length(&attachment, 0) as usize);
+
+        // Both chunks have the same length so only the authenticated data tells them apart
+        assert!(matches!(
+            decrypt_attachment_chunk(&crypto, &attachment, 1, first),
+            Err(AttachmentError::Decrypt(1))
+        ));
+        assert!(matches!(
+            decrypt_attachment_chunk(&crypto, &attachment, 0, last),
+            Err(AttachmentError::Decrypt(0))
+        ));
+
+        let swapped = [last, first].concat();
+        assert!(matches!(
+            decrypt_attachment(&crypto, &attachment, &swapped),
+            Err(AttachmentError::HashMismatch)
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_file() {
+        let crypto = RustCrypto::default();
+        let data = data(CHUNK_SIZE as usize * 2);
+        let (ciphertext, attachment) =
+            encrypt_attachment(&crypto, &data, MIME_TYPE.to_owned()).unwrap();
+        let first = &ciphertext[..sealed_chunk_length(&attachment, 0) as usize];
+
+        assert!(matches!(
+            decrypt_attachment(&crypto, &attachment, first),
+            Err(AttachmentError::LengthMismatch { .. })
+        ));
+
+        // A file cut off after the first chunk claims to be shorter, which marks the first chunk as the last one
+        let truncated = Attachment {
+            size: CHUNK_SIZE.into(),
+            hash: crypto.hash(CIPHERSUITE.hash_algorithm(), first).unwrap(),
+            ..attachment
+        };
+        assert!(matches!(
+            decrypt_attachment(&crypto, &truncated, first),
+            Err(AttachmentError::Decrypt(0))
+        ));
+    }
+
+    #[test]
+    fn rejects_modified_chunk() {
+        let crypto = RustCrypto::default();
+        let (ciphertext, attachment) =
+            encrypt_attachment(&crypto, &data(100), MIME_TYPE.to_owned()).unwrap();
+        let mut modified = ciphertext.clone();
+        modified[0] ^= 1;
+
+        assert!(matches!(
+            decrypt_attachment_chunk(&crypto, &attachment, 0, &modified),
+            Err(AttachmentError::Decrypt(0))
+        ));
+        assert!(matches!(
+            decrypt_attachment(&crypto, &attachment, &modified),
+            Err(AttachmentError::HashMismatch)
+        ));
+    }
+}
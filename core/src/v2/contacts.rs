@@ -0,0 +1,186 @@
+use std::collections::{HashMap, HashSet};
+
+use base64::prelude::*;
+use openmls::prelude::GroupId;
+use serde::{Deserialize, Serialize};
+use tsify::Tsify;
+
+use crate::Friend;
+
+/// What we know about a friend across all groups
+#[derive(Serialize, Deserialize, Default)]
+struct Contact {
+    /// The most recent name the friend appeared with
+    name: Option<String>,
+    /// The signature keys we have seen the friend use
+    signature_keys: Vec<Vec<u8>>,
+    /// The signature key we compared the safety number of with the friend
+    verified_key: Option<Vec<u8>>,
+    /// The groups we share with the friend
+    groups: HashSet<GroupId>,
+    /// If welcomes and messages from the friend are rejected
+    blocked: bool,
+}
+
+/// A friend as shown in the contact book
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct ContactInfo {
+    pub id: String,
+    pub name: Option<String>,
+    pub verified: bool,
+    pub blocked: bool,
+    /// The ids of the groups we share with the friend
+    pub groups: Vec<String>,
+}
+
+/// The friends we know of through the groups we share with them.
+/// Kept in the client state so it does not need to be rebuilt by the app.
+#[derive(Serialize, Deserialize, Default)]
+pub(super) struct ContactBook {
+    contacts: HashMap<String, Contact>,
+}
+
+impl ContactBook {
+    /// Records that the friend is a member of the group with the signature key.
+    /// A signature key we have not seen the friend use before changes the safety number and revokes the verification.
+    /// Returns if the signature key changed.
+    pub(super) fn record(
+        &mut self,
+        friend: &Friend,
+        signature_key: &[u8],
+        group_id: &GroupId,
+    ) -> bool {
+        let contact = self.contacts.entry(friend.id.clone()).or_default();
+        if friend.name.is_some() {
+            contact.name.clone_from(&friend.name);
+        }
+        contact.groups.insert(group_id.clone());
+
+        let is_known = contact
+            .signature_keys
+            .iter()
+            .any(|known| known == signature_key);
+        if is_known {
+            return false;
+        }
+
+        let changed = !contact.signature_keys.is_empty();
+        contact.signature_keys.push(signature_key.to_vec());
+        if contact
+            .verified_key
+            .as_ref()
+            .is_some_and(|verified| verified != signature_key)
+        {
+            contact.verified_key = None;
+        }
+
+        changed
+    }
+
+    /// Records that the friend is no longer a member of the group
+    pub(super) fn remove_member(&mut self, friend_id: &str, group_id: &GroupId) {
+        if let Some(contact) = self.contacts.get_mut(friend_id) {
+            contact.groups.remove(group_id);
+        }
+    }
+
+    /// Records that we are no longer a member of the group
+    pub(super) fn remove_group(&mut self, group_id: &GroupId) {
+        for contact in self.contacts.values_mut() {
+            contact.groups.remove(group_id);
+        }
+    }
+
+    pub(super) fn verify(&mut self, friend_id: &str, signature_key: &[u8]) {
+        let contact = self.contacts.entry(friend_id.to_owned()).or_default();
+        if !contact
+            .signature_keys
+            .iter()
+            .any(|known| known == signature_key)
+        {
+            contact.signature_keys.push(signature_key.to_vec());
+        }
+        contact.verified_key = Some(signature_key.to_vec());
+    }
+
+    /// Returns if the friend was verified before
+    pub(super) fn unverify(&mut self, friend_id: &str) -> bool {
+        self.contacts
+            .get_mut(friend_id)
+            .and_then(|contact| contact.verified_key.take())
+            .is_some()
+    }
+
+    pub(super) fn is_verified(&self, friend_id: &str) -> bool {
+        self.contacts
+            .get(friend_id)
+            .is_some_and(|contact| contact.verified_key.is_some())
+    }
+
+    /// Blocks the friend even if we do not share a group with them yet
+    pub(super) fn block(&mut self, friend_id: &str) {
+        self.contacts
+            .entry(friend_id.to_owned())
+            .or_default()
+            .blocked = true;
+    }
+
+    /// Returns if the friend was blocked before
+    pub(super) fn unblock(&mut self, friend_id: &str) -> bool {
+        self.contacts
+            .get_mut(friend_id)
+            .is_some_and(|contact| std::mem::take(&mut contact.blocked))
+    }
+
+    pub(super) fn is_blocked(&self, friend_id: &str) -> bool {
+        self.contacts
+            .get(friend_id)
+            .is_some_and(|contact| contact.blocked)
+    }
+
+    /// Returns if the friend used the signature key in a group we share before
+    pub(super) fn knows_key(&self, friend_id: &str, signature_key: &[u8]) -> bool {
+        self.contacts.get(friend_id).is_some_and(|contact| {
+            contact
+                .signature_keys
+                .iter()
+                .any(|known| known == signature_key)
+        })
+    }
+
+    pub(super) fn blocked(&self) -> Vec<String> {
+        self.contacts
+            .iter()
+            .filter(|(_, contact)| contact.blocked)
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    pub(super) fn get(&self, friend_id: &str) -> Option<ContactInfo> {
+        self.contacts
+            .get(friend_id)
+            .map(|contact| contact_info(friend_id, contact))
+    }
+
+    pub(super) fn list(&self) -> Vec<ContactInfo> {
+        self.contacts
+            .iter()
+            .map(|(id, contact)| contact_info(id, contact))
+            .collect()
+    }
+}
+
+fn contact_info(id: &str, contact: &Contact) -> ContactInfo {
+    ContactInfo {
+        id: id.to_owned(),
+        name: contact.name.clone(),
+        verified: contact.verified_key.is_some(),
+        blocked: contact.blocked,
+        groups: contact
+            .groups
+            .iter()
+            .map(|group_id| BASE64_URL_SAFE_NO_PAD.encode(group_id.as_slice()))
+            .collect(),
+    }
+}
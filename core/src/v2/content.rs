@@ -0,0 +1,274 @@
+//! The encoding of the content of application messages.
+//! Clients that only understand plain text decode the postcard serialized [`MessageContent`] and ignore the bytes that
+//! follow it. Everything added after plain text is appended as [`MessageExtension`] behind a fallback text, so these
+//! clients still show something sensible for replies, edits, deletions and reactions.
+//!
+//! Control messages like receipts have no text to fall back to and should not be shown by these clients. They start
+//! with a zero byte where text messages start with the length of the non-empty `sent_at`, which fails to decode.
+//!
+//! Layout of text messages: `[MessageContent..., MessageExtension...]`
+//! Layout of control messages: `[0, Control...]`
+
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::{Attachment, Content, Friend, ID_LENGTH, Message, MessageContent};
+
+/// Follows the [`MessageContent`] in messages from clients that support more than plain text
+#[derive(Serialize, Deserialize)]
+struct MessageExtension {
+    /// Identifies the message so later messages can refer to it
+    id: String,
+    payload: Payload,
+}
+
+/// What the message does with the text in front of it
+#[derive(Serialize, Deserialize)]
+enum Payload {
+    Text,
+    Reply {
+        reply_to: String,
+    },
+    /// The text replaces the text of the edited message
+    Edit {
+        edited: String,
+    },
+    /// The text is only a fallback
+    Delete {
+        deleted: String,
+    },
+    /// The text is only a fallback
+    Reaction {
+        reacted_to: String,
+        emoji: String,
+        removed: bool,
+    },
+    /// The text is shown with the attachment unless it is only a fallback
+    Attachment {
+        attachment: Attachment,
+        has_text: bool,
+    },
+}
+
+const CONTROL_MARKER: u8 = 0;
+
+/// Marks messages that are only useful while the recipient is online, like typing indicators.
+/// Set as authenticated data of the MLS message which is not encrypted, so the delivery service can read it and skip
+/// keeping these messages for offline recipients.
+pub(super) const EPHEMERAL_AAD: &[u8] = b"ephemeral";
+
+/// Messages about other messages that are not shown as messages themselves
+#[derive(Serialize, Deserialize)]
+enum Control {
+    Receipt {
+        kind: ReceiptKind,
+        /// The ids of the messages the receipt is for
+        ids: Vec<String>,
+    },
+    Typing {
+        /// If the sender started or stopped typing
+        typing: bool,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub(super) enum ReceiptKind {
+    Delivered,
+    Read,
+}
+
+pub(super) fn encode_receipt(
+    kind: ReceiptKind,
+    ids: Vec<String>,
+) -> Result<Vec<u8>, postcard::Error> {
+    postcard::to_extend(&Control::Receipt { kind, ids }, vec![CONTROL_MARKER])
+}
+
+pub(super) fn encode_typing(typing: bool) -> Result<Vec<u8>, postcard::Error> {
+    postcard::to_extend(&Control::Typing { typing }, vec![CONTROL_MARKER])
+}
+
+/// The id of the message if it has one, together with the id of its group
+pub(super) fn message_id(message: &Message) -> Option<(&str, &str)> {
+    match message {
+        Message::Private {
+            group_id,
+            id: Some(id),
+            ..
+        }
+        | Message::Reply { group_id, id, .. }
+        | Message::Edit { group_id, id, .. }
+        | Message::Delete { group_id, id, .. }
+        | Message::Reaction { group_id, id, .. }
+        | Message::Attachment { group_id, id, .. } => Some((group_id, id)),
+        _ => None,
+    }
+}
+
+/// Generates a new message id and encodes the content with it.
+/// Returns the id and the encoded message.
+pub(super) fn encode_content(
+    sent_at: OffsetDateTime,
+    content: Content,
+) -> Result<(String, Vec<u8>), postcard::Error> {
+    let (text, payload) = match content {
+        Content::Text { text } => (text, Payload::Text),
+        Content::Reply { reply_to, text } => (text, Payload::Reply { reply_to }),
+        Content::Edit { edited, text } => (text, Payload::Edit { edited }),
+        Content::Delete { deleted } => {
+            ("Deleted a message".to_owned(), Payload::Delete { deleted })
+        }
+        Content::Reaction {
+            reacted_to,
+            emoji,
+            removed,
+        } => {
+            let text = if removed {
+                format!("Removed the reaction {emoji}")
+            } else {
+                emoji.clone()
+            };
+            let payload = Payload::Reaction {
+                reacted_to,
+                emoji,
+                removed,
+            };
+            (text, payload)
+        }
+        Content::Attachment { attachment, text } => {
+            let has_text = !text.is_empty();
+            let text = if has_text {
+                text
+            } else {
+                format!("Sent a file of type {}", attachment.mime_type)
+            };
+            (
+                text,
+                Payload::Attachment {
+                    attachment,
+                    has_text,
+                },
+            )
+        }
+    };
+
+    let id = nanoid!(ID_LENGTH);
+    let data = postcard::to_allocvec(&MessageContent { sent_at, text })?;
+    let extension = MessageExtension {
+        id: id.clone(),
+        payload,
+    };
+    let data = postcard::to_extend(&extension, data)?;
+
+    Ok((id, data))
+}
+
+/// Decodes the content of an application message into the message to show.
+/// Messages without or with an unknown extension are shown as plain text.
+pub(super) fn decode_content(
+    group_id: String,
+    sender: Friend,
+    data: &[u8],
+) -> Result<Message, postcard::Error> {
+    if let Some((&CONTROL_MARKER, control)) = data.split_first() {
+        let message = match postcard::from_bytes(control)? {
+            Control::Receipt {
+                kind: ReceiptKind::Delivered,
+                ids,
+            } => Message::Delivered {
+                group_id,
+                sender,
+                ids,
+            },
+            Control::Receipt {
+                kind: ReceiptKind::Read,
+                ids,
+            } => Message::Read {
+                group_id,
+                sender,
+                ids,
+            },
+            Control::Typing { typing } => Message::Typing {
+                group_id,
+                sender,
+                typing,
+            },
+        };
+        return Ok(message);
+    }
+
+    let (content, rest) = postcard::take_from_bytes::<MessageContent>(data)?;
+    // Extensions from newer versions can not be read and fall back to the text as well
+    let Ok(extension) = postcard::from_bytes::<MessageExtension>(rest) else {
+        return Ok(Message::Private {
+            group_id,
+            sender,
+            id: None,
+            content,
+        });
+    };
+
+    let id = extension.id;
+    let message = match extension.payload {
+        Payload::Text => Message::Private {
+            group_id,
+            sender,
+            id: Some(id),
+            content,
+        },
+        Payload::Reply { reply_to } => Message::Reply {
+            group_id,
+            sender,
+            id,
+            reply_to,
+            content,
+        },
+        Payload::Edit { edited } => Message::Edit {
+            group_id,
+            sender,
+            id,
+            edited,
+            content,
+        },
+        Payload::Delete { deleted } => Message::Delete {
+            group_id,
+            sender,
+            id,
+            deleted,
+            sent_at: content.sent_at,
+        },
+        Payload::Reaction {
+            reacted_to,
+            emoji,
+            removed,
+        } => Message::Reaction {
+            group_id,
+            sender,
+            id,
+            reacted_to,
+            emoji,
+            removed,
+            sent_at: content.sent_at,
+        },
+        Payload::Attachment {
+            attachment,
+            has_text,
+        } => Message::Attachment {
+            group_id,
+            sender,
+            id,
+            attachment,
+            content: MessageContent {
+                text: if has_text {
+                    content.text
+                } else {
+                    String::new()
+                },
+                ..content
+            },
+        },
+    };
+
+    Ok(message)
+}
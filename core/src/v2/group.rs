@@ -0,0 +1,162 @@
+use std::collections::VecDeque;
+
+use openmls::prelude::GroupId;
+use serde::{Deserialize, Serialize};
+use time::{Duration, OffsetDateTime};
+use tsify::Tsify;
+
+/// Decides when our leaf key in a group is due to be rotated.
+/// Rotating regularly limits how long a compromised key stays useful to an attacker (post-compromise security).
+#[derive(Serialize, Deserialize, Tsify, Clone, Copy)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+pub struct RotationPolicy {
+    /// The maximum time in seconds since the last rotation
+    pub max_age_seconds: u32,
+    /// The maximum number of application messages sent and received since the last rotation
+    pub max_messages: u32,
+}
+
+impl Default for RotationPolicy {
+    fn default() -> Self {
+        Self {
+            // One week
+            max_age_seconds: 7 * 24 * 60 * 60,
+            max_messages: 100,
+        }
+    }
+}
+
+/// How many messages per group are remembered to recognize them when they are delivered again
+const SEEN_WINDOW: usize = 256;
+
+/// The most recent messages we processed in a group.
+/// The delivery service might deliver a batch again, for example after a reconnect. OpenMLS can not decrypt a message
+/// twice as its key is deleted after the first use, so messages are recognized by the hash of their ciphertext before
+/// processing them. Messages sent again with the same id, but encrypted anew, are recognized by their id.
+#[derive(Serialize, Deserialize, Default)]
+pub(super) struct SeenMessages {
+    ciphertexts: VecDeque<Vec<u8>>,
+    ids: VecDeque<String>,
+}
+
+impl SeenMessages {
+    pub(super) fn contains_ciphertext(&self, hash: &[u8]) -> bool {
+        self.ciphertexts.iter().any(|seen| seen == hash)
+    }
+
+    pub(super) fn insert_ciphertext(&mut self, hash: Vec<u8>) {
+        if self.ciphertexts.len() == SEEN_WINDOW {
+            self.ciphertexts.pop_front();
+        }
+        self.ciphertexts.push_back(hash);
+    }
+
+    /// Returns if the id was not seen before
+    pub(super) fn insert_id(&mut self, id: &str) -> bool {
+        if self.ids.iter().any(|seen| seen == id) {
+            return false;
+        }
+
+        if self.ids.len() == SEEN_WINDOW {
+            self.ids.pop_front();
+        }
+        self.ids.push_back(id.to_owned());
+        true
+    }
+}
+
+/// How many messages from future epochs are kept per group until the commit moving the group there arrives
+const MAX_PENDING_MESSAGES: usize = 32;
+
+#[derive(Serialize, Deserialize)]
+struct PendingMessage {
+    epoch: u64,
+    /// The serialized private message
+    data: Vec<u8>,
+}
+
+/// Messages that arrived before the commit moving the group to their epoch.
+/// The delivery service does not guarantee the order of messages from different senders, so a friend might already
+/// send messages in the next epoch while the commit is still on its way to us.
+#[derive(Serialize, Deserialize, Default)]
+pub(super) struct PendingMessages {
+    messages: VecDeque<PendingMessage>,
+    /// The messages dropped to make room for newer ones since this was last reported
+    expired: u32,
+}
+
+impl PendingMessages {
+    pub(super) fn is_empty(&self) -> bool {
+        self.messages.is_empty() && self.expired == 0
+    }
+
+    /// Keeps the message until the group reaches its epoch. Drops the oldest message if there are too many.
+    pub(super) fn push(&mut self, epoch: u64, data: Vec<u8>) {
+        if self.messages.len() == MAX_PENDING_MESSAGES {
+            self.messages.pop_front();
+            self.expired = self.expired.saturating_add(1);
+        }
+        self.messages.push_back(PendingMessage { epoch, data });
+    }
+
+    /// Removes the messages that can be processed in the current epoch of the group.
+    /// Messages from epochs the group already moved past are returned as well to report them as failed.
+    pub(super) fn take_ready(&mut self, epoch: u64) -> Vec<Vec<u8>> {
+        let (ready, pending) = std::mem::take(&mut self.messages)
+            .into_iter()
+            .partition::<VecDeque<_>, _>(|message| message.epoch <= epoch);
+        self.messages = pending;
+        ready.into_iter().map(|message| message.data).collect()
+    }
+
+    /// Returns how many messages were dropped since this was last called
+    pub(super) fn take_expired(&mut self) -> u32 {
+        std::mem::take(&mut self.expired)
+    }
+}
+
+/// State of a group on the application layer that is not managed by OpenMLS
+#[derive(Serialize, Deserialize)]
+pub(super) struct GroupState {
+    /// When we last rotated our leaf key in the group. Joining or creating the group counts as rotation.
+    rotated_at: OffsetDateTime,
+    /// The application messages sent and received since we last rotated our leaf key
+    messages_since_rotation: u32,
+    /// If the other members are told when we read their messages
+    pub(super) read_receipts: bool,
+    pub(super) seen: SeenMessages,
+    pub(super) pending: PendingMessages,
+    /// The messages that failed to be processed in a row, including pending messages dropped before their epoch
+    pub(super) failures: u32,
+    /// The group this group was created to recover after one of us lost their state of it.
+    /// Allows keeping the history of both groups together.
+    pub(super) replaces: Option<GroupId>,
+}
+
+impl GroupState {
+    pub(super) fn new() -> Self {
+        Self {
+            rotated_at: OffsetDateTime::now_utc(),
+            messages_since_rotation: 0,
+            read_receipts: true,
+            seen: SeenMessages::default(),
+            pending: PendingMessages::default(),
+            failures: 0,
+            replaces: None,
+        }
+    }
+
+    pub(super) fn record_message(&mut self) {
+        self.messages_since_rotation = self.messages_since_rotation.saturating_add(1);
+    }
+
+    pub(super) fn record_rotation(&mut self) {
+        self.rotated_at = OffsetDateTime::now_utc();
+        self.messages_since_rotation = 0;
+    }
+
+    pub(super) fn is_rotation_due(&self, policy: &RotationPolicy, now: OffsetDateTime) -> bool {
+        let max_age = Duration::seconds(policy.max_age_seconds.into());
+        now - self.rotated_at >= max_age || self.messages_since_rotation >= policy.max_messages
+    }
+}
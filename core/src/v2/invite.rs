@@ -0,0 +1,441 @@
+//! The format of the invites we share as links.
+//! Invites are a key package wrapped in an envelope that starts with a magic byte and a version.
+//! The version allows changing the envelope while still reading invites that were shared before.
+//! Invites from before the envelope existed are the plain postcard serialized key package and start with a zero byte.
+//!
+//! Layout of version 1: `[MAGIC, 1, format, key package...]`
+
+use std::ops::Range;
+
+use base64::prelude::*;
+use openmls::prelude::{
+    ApplicationIdExtension, Capabilities, Credential, Extension, Extensions, KeyPackage,
+    KeyPackageIn, Lifetime, ProtocolVersion, UnknownExtension,
+};
+use tls_codec::{Deserialize, Serialize as _, VLBytes};
+
+use crate::{CIPHERSUITE, LEAF_NODE_APPLICATION_ID, leaf_node_capabilities};
+
+/// Marks the start of an enveloped invite.
+/// Legacy invites start with the postcard encoding of the protocol version which is zero.
+const MAGIC: u8 = 0x4D;
+const VERSION: u8 = 1;
+
+/// How the key package is encoded within the envelope
+#[derive(Clone, Copy)]
+#[repr(u8)]
+enum Format {
+    /// The postcard serialized key package
+    Postcard = 0,
+    /// The TLS serialized key package without the fields that we can rebuild
+    Compact = 1,
+}
+
+/// Flags of the compact format that mark which fields have been left out
+const DEFAULT_CAPABILITIES: u8 = 0b01;
+const DERIVED_APPLICATION_ID: u8 = 0b10;
+
+#[derive(Debug, thiserror::Error)]
+pub enum EncodeInviteError {
+    #[error("Error serializing key package: {0}")]
+    Tls(#[from] tls_codec::Error),
+    #[error("Error serializing key package: {0}")]
+    Postcard(#[from] postcard::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DecodeInviteError {
+    #[error("Error decoding invite: {0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("Invite is empty")]
+    Empty,
+    #[error(
+        "Invite version {0} is not supported. The invite might have been created by a newer version"
+    )]
+    UnknownVersion(u8),
+    #[error(
+        "Invite format {0} is not supported. The invite might have been created by a newer version"
+    )]
+    UnknownFormat(u8),
+    #[error(
+        "Compact invite uses unknown flags {0:#010b}. The invite might have been created by a newer version"
+    )]
+    UnknownFlags(u8),
+    #[error("Invite ended before the {0} was read")]
+    Truncated(&'static str),
+    #[error("Compact invite left out the application id but the leaf node does not contain one")]
+    MissingApplicationId,
+    #[error("Error deserializing key package from invite: {0}")]
+    Postcard(#[from] postcard::Error),
+    #[error("Error deserializing key package from invite: {0}")]
+    Tls(#[from] tls_codec::Error),
+}
+
+pub(super) fn encode_invite(key_package: &KeyPackage) -> Result<String, EncodeInviteError> {
+    let mut data = vec![MAGIC, VERSION];
+    match encode_compact(key_package)? {
+        Some(compact) => {
+            data.push(Format::Compact as u8);
+            data.extend(compact);
+        }
+        None => {
+            data.push(Format::Postcard as u8);
+            data.extend(postcard::to_allocvec(key_package)?);
+        }
+    }
+
+    Ok(BASE64_URL_SAFE_NO_PAD.encode(data))
+}
+
+pub(super) fn decode_invite(encoded_invite: &str) -> Result<KeyPackageIn, DecodeInviteError> {
+    let data = BASE64_URL_SAFE_NO_PAD.decode(encoded_invite)?;
+    let (&first, rest) = data.split_first().ok_or(DecodeInviteError::Empty)?;
+    if first != MAGIC {
+        // Legacy invite without envelope
+        return Ok(postcard::from_bytes(&data)?);
+    }
+
+    let (&version, rest) = rest
+        .split_first()
+        .ok_or(DecodeInviteError::Truncated("version"))?;
+    if version != VERSION {
+        return Err(DecodeInviteError::UnknownVersion(version));
+    }
+
+    let (&format, body) = rest
+        .split_first()
+        .ok_or(DecodeInviteError::Truncated("format"))?;
+    match format {
+        format if format == Format::Postcard as u8 => Ok(postcard::from_bytes(body)?),
+        format if format == Format::Compact as u8 => decode_compact(body),
+        format => Err(DecodeInviteError::UnknownFormat(format)),
+    }
+}
+
+/// The TLS encoded key package split into the fields the compact format treats separately
+struct Fields {
+    /// Protocol version and ciphersuite
+    header: Range<usize>,
+    /// Init key, encryption key, signature key and credential
+    keys: Range<usize>,
+    capabilities: Range<usize>,
+    /// Leaf node source with lifetime, leaf node extensions and leaf node signature
+    leaf_node: Range<usize>,
+    leaf_node_extensions: Range<usize>,
+    extensions: Range<usize>,
+    signature: Range<usize>,
+}
+
+/// Reads the positions of the fields in the TLS encoding of the key package
+fn split_fields(data: &[u8]) -> Result<Fields, tls_codec::Error> {
+    let mut cursor = data;
+    // Deserializing advances the cursor so the position is what has been read so far
+    let position = |cursor: &[u8]| data.len() - cursor.len();
+
+    <[u8; 4]>::tls_deserialize(&mut cursor)?;
+    let header = 0..position(cursor);
+
+    let start = position(cursor);
+    for _ in 0..3 {
+        VLBytes::tls_deserialize(&mut cursor)?;
+    }
+    Credential::tls_deserialize(&mut cursor)?;
+    let keys = start..position(cursor);
+
+    let start = position(cursor);
+    Capabilities::tls_deserialize(&mut cursor)?;
+    let capabilities = start..position(cursor);
+
+    let leaf_node_start = position(cursor);
+    u8::tls_deserialize(&mut cursor)?;
+    Lifetime::tls_deserialize(&mut cursor)?;
+    let start = position(cursor);
+    Extensions::tls_deserialize(&mut cursor)?;
+    let leaf_node_extensions = start..position(cursor);
+    VLBytes::tls_deserialize(&mut cursor)?;
+    let leaf_node = leaf_node_start..position(cursor);
+
+    let start = position(cursor);
+    Extensions::tls_deserialize(&mut cursor)?;
+    let extensions = start..position(cursor);
+    let signature = position(cursor)..data.len();
+
+    Ok(Fields {
+        header,
+        keys,
+        capabilities,
+        leaf_node,
+        leaf_node_extensions,
+        extensions,
+        signature,
+    })
+}
+
+fn default_header() -> Result<Vec<u8>, tls_codec::Error> {
+    let mut header = ProtocolVersion::Mls10.tls_serialize_detached()?;
+    header.extend(CIPHERSUITE.tls_serialize_detached()?);
+    Ok(header)
+}
+
+/// The key package extensions we create for the application id in the leaf node extensions.
+/// Key packages carry the same application id in both to identify the friend before and after joining a group.
+fn derived_extensions(leaf_node_extensions: &[u8]) -> Result<Option<Vec<u8>>, tls_codec::Error> {
+    let leaf_node_extensions = Extensions::tls_deserialize_exact(leaf_node_extensions)?;
+    leaf_node_extensions
+        .iter()
+        .find_map(|extension| match extension {
+            Extension::Unknown(LEAF_NODE_APPLICATION_ID, UnknownExtension(id)) => Some(id),
+            _ => None,
+        })
+        .map(|id| {
+            Extensions::single(Extension::ApplicationId(ApplicationIdExtension::new(id)))
+                .tls_serialize_detached()
+        })
+        .transpose()
+}
+
+/// Leaves out the fields of the key package that can be rebuilt when decoding.
+/// Returns `None` if the key package does not use our protocol version and ciphersuite.
+fn encode_compact(key_package: &KeyPackage) -> Result<Option<Vec<u8>>, tls_codec::Error> {
+    let data = key_package.tls_serialize_detached()?;
+    let fields = split_fields(&data)?;
+    if data[fields.header.clone()] != default_header()? {
+        return Ok(None);
+    }
+
+    let mut flags = 0;
+    let capabilities = &data[fields.capabilities.clone()];
+    if capabilities == leaf_node_capabilities().tls_serialize_detached()? {
+        flags |= DEFAULT_CAPABILITIES;
+    }
+
+    let extensions = &data[fields.extensions.clone()];
+    if derived_extensions(&data[fields.leaf_node_extensions])?.as_deref() == Some(extensions) {
+        flags |= DERIVED_APPLICATION_ID;
+    }
+
+    let mut compact = vec![flags];
+    compact.extend_from_slice(&data[fields.keys]);
+    if flags & DEFAULT_CAPABILITIES == 0 {
+        compact.extend_from_slice(capabilities);
+    }
+    compact.extend_from_slice(&data[fields.leaf_node]);
+    if flags & DERIVED_APPLICATION_ID == 0 {
+        compact.extend_from_slice(extensions);
+    }
+    compact.extend_from_slice(&data[fields.signature]);
+
+    Ok(Some(compact))
+}
+
+/// Rebuilds the TLS encoded key package from the compact format
+fn decode_compact(body: &[u8]) -> Result<KeyPackageIn, DecodeInviteError> {
+    let (&flags, mut cursor) = body
+        .split_first()
+        .ok_or(DecodeInviteError::Truncated("flags"))?;
+    if flags & !(DEFAULT_CAPABILITIES | DERIVED_APPLICATION_ID) != 0 {
+        return Err(DecodeInviteError::UnknownFlags(flags));
+    }
+
+    // Copies the next TLS encoded value from the compact body
+    fn take<T: Deserialize>(
+        cursor: &mut &[u8],
+        data: &mut Vec<u8>,
+    ) -> Result<(), tls_codec::Error> {
+        let mut rest = *cursor;
+        T::tls_deserialize(&mut rest)?;
+        data.extend_from_slice(&cursor[..cursor.len() - rest.len()]);
+        *cursor = rest;
+        Ok(())
+    }
+
+    let mut data = default_header()?;
+    for _ in 0..3 {
+        take::<VLBytes>(&mut cursor, &mut data)?;
+    }
+    take::<Credential>(&mut cursor, &mut data)?;
+
+    if flags & DEFAULT_CAPABILITIES == 0 {
+        take::<Capabilities>(&mut cursor, &mut data)?;
+    } else {
+        data.extend(leaf_node_capabilities().tls_serialize_detached()?);
+    }
+
+    take::<u8>(&mut cursor, &mut data)?;
+    take::<Lifetime>(&mut cursor, &mut data)?;
+    let start = data.len();
+    take::<Extensions>(&mut cursor, &mut data)?;
+    let leaf_node_extensions = data[start..].to_vec();
+    take::<VLBytes>(&mut cursor, &mut data)?;
+
+    if flags & DERIVED_APPLICATION_ID == 0 {
+        take::<Extensions>(&mut cursor, &mut data)?;
+    } else {
+        let extensions = derived_extensions(&leaf_node_extensions)?
+            .ok_or(DecodeInviteError::MissingApplicationId)?;
+        data.extend(extensions);
+    }
+
+    data.extend_from_slice(cursor);
+    Ok(KeyPackageIn::tls_deserialize_exact(data)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use openmls::prelude::{BasicCredential, Ciphersuite, CredentialWithKey};
+    use openmls_basic_credential::SignatureKeyPair;
+    use openmls_rust_crypto::OpenMlsRustCrypto;
+
+    use super::*;
+    use crate::{encode_application_id, encode_leaf_node_application_id};
+
+    struct Options {
+        ciphersuite: Ciphersuite,
+        capabilities: Option<Capabilities>,
+        last_resort: bool,
+    }
+
+    impl Default for Options {
+        fn default() -> Self {
+            Self {
+                ciphersuite: CIPHERSUITE,
+                capabilities: Some(leaf_node_capabilities()),
+                last_resort: false,
+            }
+        }
+    }
+
+    fn build_key_package(options: Options) -> KeyPackage {
+        let provider = OpenMlsRustCrypto::default();
+        let signer = SignatureKeyPair::new(options.ciphersuite.signature_algorithm()).unwrap();
+        let credential = CredentialWithKey {
+            credential: BasicCredential::new(b"friend".to_vec()).into(),
+            signature_key: signer.public().into(),
+        };
+        let name = Some("Friend".to_owned());
+
+        let mut builder = KeyPackage::builder()
+            .leaf_node_extensions(encode_leaf_node_application_id("friend", &name))
+            .key_package_extensions(encode_application_id("friend", &name));
+        if let Some(capabilities) = options.capabilities {
+            builder = builder.leaf_node_capabilities(capabilities);
+        }
+        if options.last_resort {
+            builder = builder.mark_as_last_resort();
+        }
+
+        builder
+            .build(options.ciphersuite, &provider, &signer, credential)
+            .unwrap()
+            .key_package()
+            .clone()
+    }
+
+    fn envelope(invite: &str) -> Vec<u8> {
+        BASE64_URL_SAFE_NO_PAD.decode(invite).unwrap()
+    }
+
+    fn assert_round_trip(key_package: &KeyPackage, invite: &str) {
+        let decoded = decode_invite(invite).unwrap();
+        assert_eq!(
+            decoded.tls_serialize_detached().unwrap(),
+            key_package.tls_serialize_detached().unwrap()
+        );
+    }
+
+    #[test]
+    fn compact_round_trip() {
+        let key_package = build_key_package(Options::default());
+        let invite = encode_invite(&key_package).unwrap();
+
+        let data = envelope(&invite);
+        assert_eq!(data[..3], [MAGIC, VERSION, Format::Compact as u8]);
+        assert_eq!(data[3], DEFAULT_CAPABILITIES | DERIVED_APPLICATION_ID);
+        assert!(data.len() < key_package.tls_serialize_detached().unwrap().len());
+        assert_round_trip(&key_package, &invite);
+    }
+
+    #[test]
+    fn compact_keeps_fields_that_can_not_be_rebuilt() {
+        // The last resort extension is not derived from the leaf node
+        let key_package = build_key_package(Options {
+            last_resort: true,
+            ..Options::default()
+        });
+        let invite = encode_invite(&key_package).unwrap();
+        assert_eq!(envelope(&invite)[3], DEFAULT_CAPABILITIES);
+        assert_round_trip(&key_package, &invite);
+
+        let key_package = build_key_package(Options {
+            capabilities: None,
+            ..Options::default()
+        });
+        let invite = encode_invite(&key_package).unwrap();
+        assert_eq!(envelope(&invite)[3], DERIVED_APPLICATION_ID);
+        assert_round_trip(&key_package, &invite);
+    }
+
+    #[test]
+    fn other_ciphersuite_falls_back_to_postcard() {
+        let key_package = build_key_package(Options {
+            ciphersuite: Ciphersuite::MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519,
+            ..Options::default()
+        });
+        let invite = encode_invite(&key_package).unwrap();
+        assert_eq!(
+            envelope(&invite)[..3],
+            [MAGIC, VERSION, Format::Postcard as u8]
+        );
+        assert_round_trip(&key_package, &invite);
+    }
+
+    #[test]
+    fn legacy_invite() {
+        let key_package = build_key_package(Options::default());
+        let legacy = postcard::to_allocvec(&key_package).unwrap();
+        assert_eq!(legacy[0], 0);
+        assert_round_trip(&key_package, &BASE64_URL_SAFE_NO_PAD.encode(legacy));
+    }
+
+    #[test]
+    fn rejects_unknown_envelope_values() {
+        let invite = encode_invite(&build_key_package(Options::default())).unwrap();
+        let data = envelope(&invite);
+        let decode = |data: &[u8]| decode_invite(&BASE64_URL_SAFE_NO_PAD.encode(data));
+
+        let mut unknown_version = data.clone();
+        unknown_version[1] = VERSION + 1;
+        assert!(matches!(
+            decode(&unknown_version),
+            Err(DecodeInviteError::UnknownVersion(version)) if version == VERSION + 1
+        ));
+
+        let mut unknown_format = data.clone();
+        unknown_format[2] = 2;
+        assert!(matches!(
+            decode(&unknown_format),
+            Err(DecodeInviteError::UnknownFormat(2))
+        ));
+
+        let mut unknown_flags = data.clone();
+        unknown_flags[3] |= 0b100;
+        assert!(matches!(
+            decode(&unknown_flags),
+            Err(DecodeInviteError::UnknownFlags(_))
+        ));
+
+        assert!(matches!(decode(&[]), Err(DecodeInviteError::Empty)));
+        assert!(matches!(
+            decode(&[MAGIC]),
+            Err(DecodeInviteError::Truncated("version"))
+        ));
+        assert!(matches!(
+            decode(&[MAGIC, VERSION]),
+            Err(DecodeInviteError::Truncated("format"))
+        ));
+        assert!(matches!(
+            decode(&data[..data.len() - 1]),
+            Err(DecodeInviteError::Tls(_))
+        ));
+    }
+}
@@ -0,0 +1,197 @@
+use openmls::prelude::*;
+use openmls_rust_crypto::{MemoryStorage, MemoryStorageError, RustCrypto};
+use openmls_traits::storage::StorageProvider as _;
+use serde::{Deserialize, Serialize};
+use time::{Duration, OffsetDateTime};
+
+use crate::v2::psk::invite_psk;
+
+/// How long key packages are valid for by default. Same as the OpenMLS default of 12 weeks.
+pub(super) const KEY_PACKAGE_LIFETIME_SECONDS: u32 = 60 * 60 * 24 * 7 * 12;
+
+/// The longest lifetime we accept for key packages.
+/// Leaf nodes with a longer lifetime are rejected as a leaked key package would stay usable for too long.
+/// Details: https://www.rfc-editor.org/rfc/rfc9420.html#section-7.2
+pub(super) const MAX_KEY_PACKAGE_LIFETIME_SECONDS: u32 = KEY_PACKAGE_LIFETIME_SECONDS;
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct PooledKeyPackage {
+    /// Identifies the key package in the storage and in welcomes. Also used as invite id.
+    pub(super) reference: KeyPackageRef,
+    key_package: KeyPackage,
+    /// The name shown on the invite
+    pub(super) user_name: Option<String>,
+    /// If the key package has been shared in an invite already
+    handed_out: bool,
+    /// If a welcome for the key package has to include the pre-shared key derived from the invite passphrase
+    pub(super) passphrase_protected: bool,
+    /// The group we lost our state of and asked a friend to recover with the key package
+    pub(super) recovers: Option<GroupId>,
+    expires_at: OffsetDateTime,
+}
+
+/// Keeps track of the key packages we generated for invites.
+/// The private keys of the key packages live in the storage provider and have to be removed together with the key
+/// package once it was used by a welcome or expired. Otherwise the serialized client keeps growing.
+#[derive(Serialize, Deserialize, Default)]
+pub(super) struct KeyPackagePool {
+    key_packages: Vec<PooledKeyPackage>,
+}
+
+impl KeyPackagePool {
+    pub(super) fn len(&self) -> usize {
+        self.key_packages.len()
+    }
+
+    pub(super) fn insert(
+        &mut self,
+        crypto: &RustCrypto,
+        key_package: KeyPackage,
+        user_name: Option<String>,
+        handed_out: bool,
+        lifetime_seconds: u32,
+    ) -> Result<KeyPackageRef, LibraryError> {
+        let reference = key_package.hash_ref(crypto)?;
+        let expires_at = OffsetDateTime::now_utc() + Duration::seconds(lifetime_seconds.into());
+
+        self.key_packages.push(PooledKeyPackage {
+            reference: reference.clone(),
+            key_package,
+            user_name,
+            handed_out,
+            passphrase_protected: false,
+            recovers: None,
+            expires_at,
+        });
+
+        Ok(reference)
+    }
+
+    /// Finds the key package in the pool that one of the references belongs to
+    pub(super) fn find(&self, references: &[KeyPackageRef]) -> Option<&PooledKeyPackage> {
+        self.key_packages
+            .iter()
+            .find(|entry| references.contains(&entry.reference))
+    }
+
+    /// Requires welcomes for the key package to include the pre-shared key derived from the invite passphrase.
+    /// The pre-shared key has to be stored separately.
+    pub(super) fn protect(&mut self, reference: &KeyPackageRef) {
+        if let Some(entry) = self
+            .key_packages
+            .iter_mut()
+            .find(|entry| &entry.reference == reference)
+        {
+            entry.passphrase_protected = true;
+        }
+    }
+
+    /// Links the key package to the group it is meant to recover.
+    /// The group we are welcomed to with the key package replaces the lost group.
+    pub(super) fn link_recovery(&mut self, reference: &KeyPackageRef, group_id: GroupId) {
+        if let Some(entry) = self
+            .key_packages
+            .iter_mut()
+            .find(|entry| &entry.reference == reference)
+        {
+            entry.recovers = Some(group_id);
+        }
+    }
+
+    /// The number of key packages generated ahead of time for the user name that have not been handed out yet
+    pub(super) fn unused_count(&self, user_name: &Option<String>) -> usize {
+        self.key_packages
+            .iter()
+            .filter(|entry| is_unused(entry, user_name))
+            .count()
+    }
+
+    /// Hands out a key package that was generated ahead of time for the user name
+    pub(super) fn hand_out(
+        &mut self,
+        user_name: &Option<String>,
+    ) -> Option<(&KeyPackageRef, &KeyPackage)> {
+        let entry = self
+            .key_packages
+            .iter_mut()
+            .find(|entry| is_unused(entry, user_name))?;
+
+        entry.handed_out = true;
+        Some((&entry.reference, &entry.key_package))
+    }
+
+    /// The last resort key package for the user name.
+    /// It stays valid after it was used by a welcome so it can be shared more widely than other invites.
+    pub(super) fn last_resort(
+        &self,
+        user_name: &Option<String>,
+    ) -> Option<(&KeyPackageRef, &KeyPackage)> {
+        self.key_packages
+            .iter()
+            .find(|entry| entry.key_package.last_resort() && &entry.user_name == user_name)
+            .map(|entry| (&entry.reference, &entry.key_package))
+    }
+
+    /// Removes the key packages used by a welcome.
+    /// OpenMLS already deletes the private keys of used key packages from storage except for last resort key packages
+    /// which are kept as they can be used multiple times.
+    pub(super) fn remove_consumed(&mut self, consumed: &[KeyPackageRef]) {
+        self.key_packages.retain(|entry| {
+            entry.key_package.last_resort() || !consumed.contains(&entry.reference)
+        });
+    }
+
+    /// Removes the key packages that match the predicate from the pool and storage.
+    /// Returns the number of removed key packages.
+    fn remove_where(
+        &mut self,
+        storage: &MemoryStorage,
+        predicate: impl Fn(&PooledKeyPackage) -> bool,
+    ) -> Result<usize, MemoryStorageError> {
+        let (removed, kept) = std::mem::take(&mut self.key_packages)
+            .into_iter()
+            .partition::<Vec<_>, _>(predicate);
+        self.key_packages = kept;
+
+        for entry in &removed {
+            storage.delete_key_package(&entry.reference)?;
+            if entry.passphrase_protected {
+                storage.delete_psk(&invite_psk(&entry.reference))?;
+            }
+        }
+
+        Ok(removed.len())
+    }
+
+    pub(super) fn remove_expired(
+        &mut self,
+        storage: &MemoryStorage,
+    ) -> Result<usize, MemoryStorageError> {
+        let now = OffsetDateTime::now_utc();
+        self.remove_where(storage, |entry| entry.expires_at <= now)
+    }
+
+    /// Removes the last resort key package to replace it with a new one as we only keep one around
+    pub(super) fn remove_last_resort(
+        &mut self,
+        storage: &MemoryStorage,
+    ) -> Result<usize, MemoryStorageError> {
+        self.remove_where(storage, |entry| entry.key_package.last_resort())
+    }
+
+    /// Removes the key package so it can not be used to join a group anymore.
+    /// Returns if a key package was removed.
+    pub(super) fn revoke(
+        &mut self,
+        storage: &MemoryStorage,
+        reference: &[u8],
+    ) -> Result<bool, MemoryStorageError> {
+        let removed =
+            self.remove_where(storage, |entry| entry.reference.as_slice() == reference)?;
+        Ok(removed > 0)
+    }
+}
+
+fn is_unused(entry: &PooledKeyPackage, user_name: &Option<String>) -> bool {
+    !entry.handed_out && !entry.key_package.last_resort() && &entry.user_name == user_name
+}
@@ -1,2 +1,13 @@
+mod at_rest;
+mod attachment;
+mod contacts;
+mod content;
+mod group;
+mod invite;
+mod key_package_pool;
 mod provider;
+mod psk;
 mod serializable;
+mod verification;
+
+pub use serializable::Client;
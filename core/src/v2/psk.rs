@@ -0,0 +1,32 @@
+//! Pre-shared keys derived from invite passphrases.
+//! The friend creating the invite and the friend answering it derive the same key from the passphrase they agreed on
+//! out of band. The welcome can only be processed with the key, so intercepting the invite link is not enough to add
+//! the friend to a group.
+//! Details: https://www.rfc-editor.org/rfc/rfc9420.html#section-8.4
+
+use argon2::Argon2;
+use openmls::{
+    prelude::KeyPackageRef,
+    schedule::{ExternalPsk, Psk},
+};
+
+/// The length of the derived pre-shared key. Matches the hash length of the ciphersuite.
+const PSK_LENGTH: usize = 32;
+
+/// Identifies the pre-shared key of the invite.
+/// Both sides know the key package reference so it does not need to be transmitted.
+pub(super) fn invite_psk(reference: &KeyPackageRef) -> Psk {
+    Psk::External(ExternalPsk::new(reference.as_slice().to_vec()))
+}
+
+/// Derives the pre-shared key from the passphrase.
+/// Passphrases are chosen by users and easy to guess, so a password hashing function slows down brute forcing them.
+/// The key package reference is unique per invite and used as salt.
+pub(super) fn derive_invite_psk(
+    passphrase: &str,
+    reference: &KeyPackageRef,
+) -> Result<[u8; PSK_LENGTH], argon2::Error> {
+    let mut psk = [0; PSK_LENGTH];
+    Argon2::default().hash_password_into(passphrase.as_bytes(), reference.as_slice(), &mut psk)?;
+    Ok(psk)
+}
@@ -5,20 +5,51 @@
 //! Running in the service worker allows sending MLS messages through push notifications for example and avoids state management issues
 //! with multiple tabs effectively being multiple simultaneous clients that use the same storage.
 
-use std::{collections::HashSet, rc::Rc, vec::IntoIter};
+use std::{
+    collections::{HashMap, HashSet},
+    rc::Rc,
+    vec::IntoIter,
+};
 
 use base64::prelude::*;
 use nanoid::nanoid;
-use openmls::prelude::*;
+use openmls::{
+    messages::group_info::GroupInfoError,
+    prelude::*,
+    schedule::{PreSharedKeyId, errors::PskError},
+};
 use openmls_basic_credential::SignatureKeyPair;
-use openmls_rust_crypto::MemoryStorageError;
+use openmls_rust_crypto::{MemoryStorageError, RustCrypto};
+use openmls_traits::storage::StorageProvider as _;
 use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
 use tls_codec::Serialize as _;
+use tsify::Tsify;
 use wasm_bindgen::{JsError, prelude::wasm_bindgen};
 
 use crate::{
-    ApplicationMessage, CIPHERSUITE, DecodedPackage, Friend, ID_LENGTH, Message, MessageContent,
-    encode_application_id, v2::provider::Provider,
+    ApplicationMessage, Attachment, CIPHERSUITE, Content, DecodedPackage, Friend, ID_LENGTH,
+    Message, MessageContent, OutgoingMessage, ProposedChange, ReadCredentialError,
+    decode_application_id, encode_application_id, encode_leaf_node_application_id,
+    friend_from_credential, friend_from_leaf_node, leaf_node_application_id,
+    leaf_node_capabilities,
+    v2::{
+        at_rest::{StorageEncryptionError, StorageKey, is_encrypted},
+        attachment::{decrypt_attachment, decrypt_attachment_chunk, encrypt_attachment},
+        contacts::{ContactBook, ContactInfo},
+        content::{
+            EPHEMERAL_AAD, ReceiptKind, decode_content, encode_content, encode_receipt,
+            encode_typing, message_id,
+        },
+        group::{GroupState, RotationPolicy},
+        invite::{DecodeInviteError, EncodeInviteError, decode_invite, encode_invite},
+        key_package_pool::{
+            KEY_PACKAGE_LIFETIME_SECONDS, KeyPackagePool, MAX_KEY_PACKAGE_LIFETIME_SECONDS,
+        },
+        provider::Provider,
+        psk::{derive_invite_psk, invite_psk},
+        verification::safety_number,
+    },
 };
 
 #[derive(Serialize, Deserialize)]
@@ -39,17 +70,144 @@ pub enum CreateGroupError {
 pub struct GroupNotFound;
 
 #[derive(Debug, thiserror::Error)]
-pub enum ProcessPrivateMessageError {
+#[error("Invite not found. It might have been used, revoked or expired already")]
+pub struct InviteNotFound;
+
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "Invite lifetime has to be between 1 and {MAX_KEY_PACKAGE_LIFETIME_SECONDS} seconds, got {0}"
+)]
+pub struct InvalidInviteLifetime(u32);
+
+#[derive(Debug, thiserror::Error)]
+pub enum InvitePassphraseError {
+    #[error("Error deriving key from invite passphrase: {0}")]
+    Derive(#[from] argon2::Error),
+    #[error("Error creating pre-shared key id: {0}")]
+    Id(#[from] CryptoError),
+    #[error("Error storing pre-shared key: {0}")]
+    Store(#[from] PskError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DecodeKeyPackageError {
+    #[error(transparent)]
+    Decode(#[from] DecodeInviteError),
+    #[error("Invite expired or is not valid yet")]
+    Expired,
+    #[error("Invalid invite: {0}")]
+    Verify(KeyPackageVerifyError),
+    #[error("Invite did not contain an id to contact the other client with")]
+    MissingApplicationId,
+    #[error("Invite contains an invalid id: {0}")]
+    InvalidApplicationId(#[from] std::str::Utf8Error),
+    #[error("Invalid invite credential: {0}")]
+    Credential(#[from] ReadCredentialError),
+}
+
+impl From<KeyPackageVerifyError> for DecodeKeyPackageError {
+    fn from(error: KeyPackageVerifyError) -> Self {
+        match error {
+            KeyPackageVerifyError::InvalidLifetime => Self::Expired,
+            error => Self::Verify(error),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Friend is not a member of the group")]
+pub struct MemberNotFound;
+
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "Invites with a passphrase can only be used in groups without other members as they would need the passphrase too"
+)]
+pub struct PassphraseWithOtherMembers;
+
+#[derive(Debug, thiserror::Error)]
+#[error("Can not remove ourselves from a group. Leave the group instead")]
+pub struct RemoveSelf;
+
+#[derive(Debug, thiserror::Error)]
+#[error("Can not block ourselves")]
+pub struct BlockSelf;
+
+#[derive(Debug, thiserror::Error)]
+#[error("No messages to send. Receivers reject empty batches")]
+pub struct EmptyBatch;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeserializeClientError {
+    #[error("Error deserializing client: {0}")]
+    Deserialize(#[from] postcard::Error),
+    #[error(
+        "Client was serialized with format version {0} which is newer than this version supports"
+    )]
+    UnsupportedVersion(u8),
+    #[error("Client is encrypted and has to be read with a passphrase")]
+    Encrypted,
+    #[error("Error migrating key packages of client: {0}")]
+    MigrateKeyPackages(#[from] LibraryError),
+}
+
+/// Finds the member of the group with the credential of the friend
+fn find_member(group: &MlsGroup, friend_id: &str) -> Option<Member> {
+    group.members().find(|member| {
+        BasicCredential::try_from(member.credential.clone())
+            .is_ok_and(|credential| credential.identity() == friend_id.as_bytes())
+    })
+}
+
+/// Reads the friend from the credential of the group member at the leaf index
+fn member_friend(group: &MlsGroup, index: LeafNodeIndex) -> Result<Friend, MemberFriendError> {
+    let credential = group
+        .member(index)
+        .ok_or(MemberFriendError::MemberNotFound(index.u32()))?;
+    Ok(friend_from_credential(credential.clone())?)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MemberFriendError {
+    #[error("No member at leaf index {0}")]
+    MemberNotFound(u32),
+    #[error("Error reading credential of member: {0}")]
+    ReadCredential(#[from] ReadCredentialError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProcessProtocolMessageError {
     #[error("Group not found")]
     GroupNotFound,
-    #[error("Error loading group: {0}")]
-    LoadGroup(#[from] MemoryStorageError),
+    #[error("Error accessing storage: {0}")]
+    Storage(#[from] MemoryStorageError),
     #[error("Error processing message with MLS group: {0}")]
     ProcessMessage(#[from] ProcessMessageError),
-    #[error("Unexpected message content type: {0:?}")]
-    UnexpectedMessageContent(ProcessedMessageContent),
     #[error("Error deserializing message content: {0}")]
     DeserializeMessageContent(#[from] postcard::Error),
+    #[error("Error merging commit: {0}")]
+    MergeCommit(#[from] MergeCommitError<MemoryStorageError>),
+    #[error("Error reading credential of sender: {0}")]
+    ReadCredential(#[from] ReadCredentialError),
+    #[error("Error reading member affected by proposal: {0}")]
+    MemberFriend(#[from] MemberFriendError),
+    #[error("Error serializing message to recognize duplicates: {0}")]
+    Serialize(#[from] tls_codec::Error),
+    #[error("Error hashing message to recognize duplicates: {0}")]
+    Hash(#[from] CryptoError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum UnexpectedMessageBody {
+    #[error("Group info can not be processed as a message")]
+    GroupInfo,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProcessRecoveryRequestError {
+    #[error("Error reading key package of the recovery request: {0}")]
+    Decode(#[from] DecodeKeyPackageError),
+    #[error("Recovery request was sent by {0} who is blocked")]
+    SenderBlocked(String),
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -59,13 +217,166 @@ pub enum ProcessWelcomeMessageError {
     #[error("Error processing welcome message: {0:?}")]
     ProcessWelcome(#[from] WelcomeError<MemoryStorageError>),
     #[error("Unexpected message body type for introduction message: {0:?}")]
-    UnexpectedIntroductionBody(MlsMessageBodyIn),
+    UnexpectedIntroductionBody(Box<MlsMessageBodyIn>),
     #[error("Error processing message with group")]
     GroupProcess(#[from] ProcessMessageError),
     #[error("Unexpected message content type: {0:?}")]
     UnexpectedContent(ProcessedMessageContent),
     #[error("Error deserializing message content: {0}")]
     DeserializeMessageContent(#[from] postcard::Error),
+    #[error("Error reading the member that sent the welcome or the used key package: {0}")]
+    Library(#[from] LibraryError),
+    #[error("Error reading credential of the member that sent the welcome: {0}")]
+    ReadCredential(#[from] ReadCredentialError),
+    #[error(
+        "Introduction claims to be from {claimed} but was sent by the member with the credential of {actual}"
+    )]
+    IntroductionIdMismatch { claimed: String, actual: String },
+    #[error(
+        "Welcome does not answer any of our invites. The invite might have been revoked or expired"
+    )]
+    InviteNotFound,
+    #[error("Invite is protected by a passphrase but the welcome was created without one")]
+    PassphraseMissing,
+    #[error("Welcome was created with a different passphrase than the invite")]
+    PassphraseMismatch,
+    #[error("Error deleting pre-shared key of the invite: {0}")]
+    Storage(#[from] MemoryStorageError),
+    #[error("Welcome was sent by {0} who is blocked")]
+    SenderBlocked(String),
+}
+
+#[derive(Debug, thiserror::Error)]
+enum ProcessBatchItemError {
+    #[error(transparent)]
+    ProcessProtocolMessage(#[from] ProcessProtocolMessageError),
+    #[error(transparent)]
+    ProcessWelcome(#[from] ProcessWelcomeMessageError),
+    #[error(transparent)]
+    ProcessRecoveryRequest(#[from] ProcessRecoveryRequestError),
+    #[error(transparent)]
+    UnexpectedMessageBody(#[from] UnexpectedMessageBody),
+}
+
+/// An invite to share with a friend so they can add us to a group
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct Invite {
+    /// Identifies the invite to revoke it later and to match it with the welcome of the friend that used it
+    pub id: String,
+    /// The user name the invite was created with
+    pub label: Option<String>,
+    /// The encoded key package to share in the invite link
+    pub payload: String,
+}
+
+impl Invite {
+    fn new(
+        reference: &KeyPackageRef,
+        key_package: &KeyPackage,
+        label: Option<String>,
+    ) -> Result<Self, EncodeInviteError> {
+        Ok(Self {
+            id: encode_invite_id(reference),
+            label,
+            payload: encode_invite(key_package)?,
+        })
+    }
+}
+
+fn encode_invite_id(reference: &KeyPackageRef) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(reference.as_slice())
+}
+
+/// A message encrypted for a group
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct SentMessage {
+    /// Identifies the message in replies, edits, deletions and reactions
+    pub id: String,
+    /// The payload to send to the delivery service
+    #[serde(with = "serde_bytes")]
+    #[tsify(type = "Uint8Array")]
+    pub payload: Vec<u8>,
+}
+
+/// Messages encrypted for a group and batched into one payload
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct SentMessages {
+    /// The ids of the messages in the order they were given
+    pub ids: Vec<String>,
+    /// The payload to send to the delivery service
+    #[serde(with = "serde_bytes")]
+    #[tsify(type = "Uint8Array")]
+    pub payload: Vec<u8>,
+}
+
+/// A file encrypted for sending it as attachment
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct EncryptedAttachment {
+    /// The encrypted file to upload separately from the message
+    #[serde(with = "serde_bytes")]
+    #[tsify(type = "Uint8Array")]
+    pub ciphertext: Vec<u8>,
+    /// The attachment to send to the group once the ciphertext was uploaded
+    pub attachment: Attachment,
+}
+
+/// How many messages of a group have to fail to be processed in a row before we consider the group out of sync
+const DESYNC_THRESHOLD: u32 = 3;
+
+/// How many groups we do not know are tracked for failed messages.
+/// Anyone can send messages with made up group ids, so they are only kept in memory and limited.
+const MAX_UNKNOWN_GROUPS: usize = 32;
+
+/// A new group that replaces a group a friend lost their state of
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct Recovery {
+    pub group_id: String,
+    /// The welcome to send to the friend
+    #[serde(with = "serde_bytes")]
+    #[tsify(type = "Uint8Array")]
+    pub welcome: Vec<u8>,
+}
+
+/// The result of inviting a new member to a group
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct Invitation {
+    /// The welcome and introduction to send to the invited friend
+    #[serde(with = "serde_bytes")]
+    #[tsify(type = "Uint8Array")]
+    pub welcome: Vec<u8>,
+    /// The commit adding the invited friend to send to the existing members of the group.
+    /// Is `None` if there are no other members that need to be informed of the change.
+    #[serde(with = "serde_bytes")]
+    #[tsify(type = "Uint8Array | undefined")]
+    pub commit: Option<Vec<u8>>,
+    /// If the friend uses a different signature key than in the groups we shared before.
+    /// Their safety numbers changed and they are no longer verified.
+    pub key_changed: bool,
+}
+
+/// Prefix of serialized clients that is followed by the format version.
+/// Clients serialized before the format was versioned start with the length of their id instead, which is never the
+/// first byte of the prefix.
+const FORMAT_PREFIX: &[u8] = b"meal-client";
+
+/// Has to be increased whenever the layout of [`Client`] changes.
+/// Postcard is not self-describing, so older layouts can only be read with their own types and have to be migrated.
+const FORMAT_VERSION: u8 = 1;
+
+/// The layout of the client before the format was versioned
+#[derive(Deserialize)]
+struct UnversionedClient {
+    id: Rc<str>,
+    user: User,
+    groups: HashSet<GroupId>,
+    key_packages: Vec<KeyPackage>,
+    provider: Provider,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -73,12 +384,24 @@ pub enum ProcessWelcomeMessageError {
 pub struct Client {
     id: Rc<str>,
     user: User,
-    /// We only store the group ids because the groups themselves are not serializable.
+    /// We only store the group ids and application state because the groups themselves are not serializable.
     /// The group state can be retrieved from the storage provider using the group id.
-    groups: HashSet<GroupId>,
+    groups: HashMap<GroupId, GroupState>,
+    rotation_policy: RotationPolicy,
+    /// How long invites created from now on are valid for
+    invite_lifetime_seconds: u32,
 
-    /// Need to be kept for later reference
-    key_packages: Vec<KeyPackage>,
+    /// The key packages of invites that have not been used yet
+    key_packages: KeyPackagePool,
+    /// The friends we share groups with
+    contacts: ContactBook,
+    /// The number of messages that failed to be processed in a row per group we do not know.
+    /// Counted to notice when we lost our state of a group, the groups we know count their failures themselves.
+    #[serde(skip)]
+    unknown_groups: HashMap<GroupId, u32>,
+    /// The key the client is encrypted with when saving. Only kept in memory after unlocking.
+    #[serde(skip)]
+    storage_key: Option<StorageKey>,
     provider: Provider,
 }
 
@@ -116,8 +439,13 @@ impl Client {
         let client = Client {
             id: client_id.into(),
             user,
-            groups: HashSet::new(),
-            key_packages: Vec::new(),
+            groups: HashMap::new(),
+            rotation_policy: RotationPolicy::default(),
+            invite_lifetime_seconds: KEY_PACKAGE_LIFETIME_SECONDS,
+            key_packages: KeyPackagePool::default(),
+            contacts: ContactBook::default(),
+            unknown_groups: HashMap::new(),
+            storage_key: None,
             provider,
         };
 
@@ -125,62 +453,360 @@ impl Client {
     }
 
     pub fn serialize(&self) -> Result<Vec<u8>, JsError> {
-        Ok(postcard::to_allocvec(&self)?)
+        Ok(self.serialize_versioned()?)
     }
 
+    fn serialize_versioned(&self) -> Result<Vec<u8>, postcard::Error> {
+        let mut header = FORMAT_PREFIX.to_vec();
+        header.push(FORMAT_VERSION);
+        postcard::to_extend(&self, header)
+    }
+
+    /// Reads a serialized client and migrates clients serialized by older versions
     pub fn from_serialized(bytes: &[u8]) -> Result<Self, JsError> {
         console_error_panic_hook::set_once();
-        Ok(postcard::from_bytes(bytes)?)
+        Ok(Self::deserialize_versioned(bytes)?)
     }
 
-    pub fn create_invite(&mut self, user_name: Option<String>) -> Result<String, JsError> {
-        //TODO think about ways to reduce size of key package to generate smaller invite links
-        //TODO like using a non self describing serialization format and remove
-        //TODO and remove things that do not change or where we use a default
-        //TODO adding postcard as dependency yields 9-10% smaller serialized + base64 encoded key packages
+    fn deserialize_versioned(bytes: &[u8]) -> Result<Self, DeserializeClientError> {
+        if is_encrypted(bytes) {
+            return Err(DeserializeClientError::Encrypted);
+        }
 
-        let extensions = encode_application_id(&self.id, &user_name);
+        match bytes.strip_prefix(FORMAT_PREFIX) {
+            Some([FORMAT_VERSION, client @ ..]) => Ok(postcard::from_bytes(client)?),
+            Some([version, ..]) => Err(DeserializeClientError::UnsupportedVersion(*version)),
+            Some([]) => Err(postcard::Error::DeserializeUnexpectedEnd.into()),
+            None => Self::migrate_unversioned(postcard::from_bytes(bytes)?),
+        }
+    }
+
+    /// Migrates a client from before the format was versioned.
+    /// Its key packages were all handed out in invites and the friends in its groups become its contacts.
+    fn migrate_unversioned(client: UnversionedClient) -> Result<Self, DeserializeClientError> {
+        let UnversionedClient {
+            id,
+            user,
+            groups,
+            key_packages,
+            provider,
+        } = client;
+
+        let mut pool = KeyPackagePool::default();
+        for key_package in key_packages {
+            // Key packages used to be created with the OpenMLS default lifetime which matches ours
+            pool.insert(
+                provider.crypto(),
+                key_package,
+                None,
+                true,
+                KEY_PACKAGE_LIFETIME_SECONDS,
+            )?;
+        }
+
+        // Contacts are best effort, a member that can not be read is left out instead of losing the whole client
+        let mut contacts = ContactBook::default();
+        for group_id in &groups {
+            let Ok(Some(group)) = MlsGroup::load(provider.storage(), group_id) else {
+                continue;
+            };
+            for member in group.members() {
+                if let Ok(friend) = friend_from_credential(member.credential)
+                    && *friend.id != *id
+                {
+                    contacts.record(&friend, &member.signature_key, group_id);
+                }
+            }
+        }
+
+        Ok(Client {
+            id,
+            user,
+            groups: groups
+                .into_iter()
+                .map(|group_id| (group_id, GroupState::new()))
+                .collect(),
+            rotation_policy: RotationPolicy::default(),
+            invite_lifetime_seconds: KEY_PACKAGE_LIFETIME_SECONDS,
+            key_packages: pool,
+            contacts,
+            unknown_groups: HashMap::new(),
+            storage_key: None,
+            provider,
+        })
+    }
+
+    /// Serializes the client encrypted with a key protected by the passphrase.
+    /// The first call creates the key. Later calls reuse it and fail if the passphrase is different, use
+    /// `change_storage_passphrase` to change it.
+    pub fn serialize_encrypted(&mut self, passphrase: &str) -> Result<Vec<u8>, JsError> {
+        if self.storage_key.is_none() {
+            self.storage_key = Some(StorageKey::new(self.provider.crypto(), passphrase)?);
+        }
+
+        let storage_key = self.unlocked_storage_key(passphrase)?;
+        let serialized = self.serialize_versioned()?;
+        Ok(storage_key.seal(self.provider.crypto(), &serialized)?)
+    }
+
+    /// Decrypts a client serialized with `serialize_encrypted`.
+    /// The client stays unlocked, so it can be saved again with the same passphrase without deriving the key again.
+    pub fn from_serialized_encrypted(bytes: &[u8], passphrase: &str) -> Result<Self, JsError> {
+        console_error_panic_hook::set_once();
+        let (serialized, storage_key) =
+            StorageKey::open(&RustCrypto::default(), bytes, passphrase)?;
+        let mut client = Self::deserialize_versioned(&serialized)?;
+        client.storage_key = Some(storage_key);
+        Ok(client)
+    }
+
+    /// If the serialized client has to be read with `from_serialized_encrypted`.
+    /// Allows migrating clients that were saved before encryption at rest.
+    pub fn is_encrypted(bytes: &[u8]) -> bool {
+        is_encrypted(bytes)
+    }
+
+    /// Protects the storage key with a new passphrase.
+    /// Only the storage key is encrypted again, so the client has to be saved with `serialize_encrypted` and the new
+    /// passphrase for the change to take effect.
+    pub fn change_storage_passphrase(
+        &mut self,
+        passphrase: &str,
+        new_passphrase: &str,
+    ) -> Result<(), JsError> {
+        let storage_key = self.unlocked_storage_key(passphrase)?;
+        self.storage_key =
+            Some(storage_key.change_passphrase(self.provider.crypto(), new_passphrase)?);
+        Ok(())
+    }
 
+    /// Replaces the storage key with a new random key.
+    /// Copies of the client saved before can not be decrypted with the new key, which limits what a leaked key reveals.
+    /// Takes effect when the client is saved with `serialize_encrypted` again.
+    pub fn rotate_storage_key(&mut self, passphrase: &str) -> Result<(), JsError> {
+        self.unlocked_storage_key(passphrase)?;
+        self.storage_key = Some(StorageKey::new(self.provider.crypto(), passphrase)?);
+        Ok(())
+    }
+
+    fn unlocked_storage_key(&self, passphrase: &str) -> Result<&StorageKey, JsError> {
+        let storage_key = self
+            .storage_key
+            .as_ref()
+            .ok_or(StorageEncryptionError::NotEncrypted)?;
+        if !storage_key.matches(self.provider.crypto(), passphrase)? {
+            return Err(StorageEncryptionError::WrongPassphrase.into());
+        }
+        Ok(storage_key)
+    }
+
+    fn build_key_package(
+        &self,
+        user_name: &Option<String>,
+        last_resort: bool,
+    ) -> Result<KeyPackage, KeyPackageNewError> {
         // Add identifier to help users identify the origin of the key package / invitation
         // Details: https://www.rfc-editor.org/rfc/rfc9420.html#section-5.3.3
+        let extensions = encode_application_id(&self.id, user_name);
 
-        let bundle = KeyPackage::builder()
-            .key_package_extensions(extensions)
-            .build(
-                CIPHERSUITE,
-                &self.provider,
-                &self.user.signature_key,
-                self.user.credential.clone(),
+        let mut builder = KeyPackage::builder()
+            .key_package_lifetime(Lifetime::new(self.invite_lifetime_seconds.into()))
+            // The leaf node extension introduces us to the other members once we are added to a group
+            .leaf_node_capabilities(leaf_node_capabilities())
+            .leaf_node_extensions(encode_leaf_node_application_id(&self.id, user_name))
+            .key_package_extensions(extensions);
+
+        if last_resort {
+            builder = builder.mark_as_last_resort();
+        }
+
+        let bundle = builder.build(
+            CIPHERSUITE,
+            &self.provider,
+            &self.user.signature_key,
+            self.user.credential.clone(),
+        )?;
+
+        Ok(bundle.key_package().clone())
+    }
+
+    /// Adds the key package to the pool and creates the invite for it
+    fn insert_invite(
+        &mut self,
+        key_package: KeyPackage,
+        user_name: Option<String>,
+    ) -> Result<Invite, JsError> {
+        let reference = self.key_packages.insert(
+            self.provider.crypto(),
+            key_package.clone(),
+            user_name.clone(),
+            true,
+            self.invite_lifetime_seconds,
+        )?;
+        Ok(Invite::new(&reference, &key_package, user_name)?)
+    }
+
+    /// Derives the pre-shared key from the invite passphrase and stores it for OpenMLS to use in the key schedule
+    fn store_invite_psk(
+        &self,
+        reference: &KeyPackageRef,
+        passphrase: &str,
+    ) -> Result<PreSharedKeyId, InvitePassphraseError> {
+        let psk = derive_invite_psk(passphrase, reference)?;
+        let psk_id = PreSharedKeyId::new(CIPHERSUITE, self.provider.rand(), invite_psk(reference))?;
+        psk_id.store(&self.provider, &psk)?;
+        Ok(psk_id)
+    }
+
+    /// Creates an invite to share with a friend.
+    /// With a passphrase the friend can only add us to a group if they know the passphrase as well.
+    pub fn create_invite(
+        &mut self,
+        user_name: Option<String>,
+        passphrase: Option<String>,
+    ) -> Result<Invite, JsError> {
+        self.key_packages.remove_expired(self.provider.storage())?;
+
+        // Prefer key packages that were generated ahead of time
+        let (reference, key_package) = match self.key_packages.hand_out(&user_name) {
+            Some((reference, key_package)) => (reference.clone(), key_package.clone()),
+            None => {
+                let key_package = self.build_key_package(&user_name, false)?;
+                let reference = self.key_packages.insert(
+                    self.provider.crypto(),
+                    key_package.clone(),
+                    user_name.clone(),
+                    true,
+                    self.invite_lifetime_seconds,
+                )?;
+                (reference, key_package)
+            }
+        };
+
+        if let Some(passphrase) = passphrase {
+            self.store_invite_psk(&reference, &passphrase)?;
+            self.key_packages.protect(&reference);
+        }
+
+        Ok(Invite::new(&reference, &key_package, user_name)?)
+    }
+
+    /// Generates key packages ahead of time until there are `count` unused ones for the user name.
+    /// Allows handing out invites without the cost of generating key packages.
+    pub fn replenish_key_packages(
+        &mut self,
+        user_name: Option<String>,
+        count: usize,
+    ) -> Result<(), JsError> {
+        self.key_packages.remove_expired(self.provider.storage())?;
+
+        let missing = count.saturating_sub(self.key_packages.unused_count(&user_name));
+        for _ in 0..missing {
+            let key_package = self.build_key_package(&user_name, false)?;
+            self.key_packages.insert(
+                self.provider.crypto(),
+                key_package,
+                user_name.clone(),
+                false,
+                self.invite_lifetime_seconds,
             )?;
+        }
+
+        Ok(())
+    }
+
+    /// Creates an invite that can be used by multiple friends as its key package is not deleted when used.
+    /// There is only one last resort key package which is replaced when the user name changes.
+    pub fn last_resort_invite(&mut self, user_name: Option<String>) -> Result<Invite, JsError> {
+        self.key_packages.remove_expired(self.provider.storage())?;
+
+        if let Some((reference, key_package)) = self.key_packages.last_resort(&user_name) {
+            return Ok(Invite::new(reference, key_package, user_name)?);
+        }
+
+        self.key_packages
+            .remove_last_resort(self.provider.storage())?;
+        let key_package = self.build_key_package(&user_name, true)?;
+        self.insert_invite(key_package, user_name)
+    }
+
+    /// Deletes the key package and private keys of the invite.
+    /// Welcomes created from the invite fail to be processed afterwards.
+    pub fn revoke_invite(&mut self, invite_id: &str) -> Result<(), JsError> {
+        let reference = BASE64_URL_SAFE_NO_PAD.decode(invite_id)?;
+        if !self
+            .key_packages
+            .revoke(self.provider.storage(), &reference)?
+        {
+            return Err(InviteNotFound.into());
+        }
+
+        Ok(())
+    }
+
+    /// Removes key packages that expired from the pool and storage.
+    /// Returns the number of removed key packages.
+    pub fn remove_expired_key_packages(&mut self) -> Result<usize, JsError> {
+        Ok(self.key_packages.remove_expired(self.provider.storage())?)
+    }
+
+    /// The number of key packages in the pool including the ones that have been handed out in invites
+    #[wasm_bindgen(getter)]
+    pub fn key_package_count(&self) -> usize {
+        self.key_packages.len()
+    }
+
+    /// How long invites created from now on are valid for in seconds
+    #[wasm_bindgen(getter)]
+    pub fn invite_lifetime_seconds(&self) -> u32 {
+        self.invite_lifetime_seconds
+    }
+
+    /// Sets how long invites created from now on are valid for.
+    /// Key packages that were generated ahead of time keep their lifetime.
+    pub fn set_invite_lifetime_seconds(&mut self, seconds: u32) -> Result<(), JsError> {
+        if seconds == 0 || seconds > MAX_KEY_PACKAGE_LIFETIME_SECONDS {
+            return Err(InvalidInviteLifetime(seconds).into());
+        }
 
-        self.key_packages.push(bundle.key_package().clone());
-        // Using postcard reduces the size by around 40 bytes or 9-10%
-        // This might not be worth the dependency but we are using it for application messages anyways
-        let data = postcard::to_allocvec(bundle.key_package())?;
-        Ok(BASE64_URL_SAFE_NO_PAD.encode(data))
+        self.invite_lifetime_seconds = seconds;
+        Ok(())
     }
 
     pub fn decode_key_package(&self, encoded_invite: &str) -> Result<DecodedPackage, JsError> {
-        let data = BASE64_URL_SAFE_NO_PAD.decode(encoded_invite)?;
-        // let package = KeyPackageIn::tls_deserialize_exact_bytes(&data).unwrap();
-        let package: KeyPackageIn = postcard::from_bytes(&data)?;
+        Ok(self.decode_key_package_inner(encoded_invite)?)
+    }
+
+    fn decode_key_package_inner(
+        &self,
+        encoded_invite: &str,
+    ) -> Result<DecodedPackage, DecodeKeyPackageError> {
+        self.validate_key_package(decode_invite(encoded_invite)?)
+    }
 
+    fn validate_key_package(
+        &self,
+        package: KeyPackageIn,
+    ) -> Result<DecodedPackage, DecodeKeyPackageError> {
+        // Validation rejects key packages outside of their lifetime
         let validated = package.validate(self.provider.crypto(), ProtocolVersion::Mls10)?;
-        let id = validated
+
+        let application_id = validated
             .extensions()
             .application_id()
-            .map(|id| str::from_utf8(id.as_slice()))
-            .transpose()?
-            .ok_or_else(|| {
-                JsError::new("Invite did not contain an id to contact the other client with")
-            })?;
-
-        let (id, friend_name) = if id.len() > ID_LENGTH {
-            let (id, friend_name) = id.split_at(ID_LENGTH);
-            (id, Some(friend_name))
-        } else {
-            (id, None)
-        };
+            .ok_or(DecodeKeyPackageError::MissingApplicationId)?;
+
+        let (id, friend_name) = decode_application_id(application_id.as_slice())?;
+        // The id is taken from the extension as it comes with the name, but only the credential is signed by the key
+        // the friend is known by
+        let credential = friend_from_credential(validated.leaf_node().credential().clone())?;
+        if id != credential.id {
+            return Err(ReadCredentialError::ApplicationIdMismatch {
+                claimed: id.to_owned(),
+                actual: credential.id,
+            }
+            .into());
+        }
 
         Ok(DecodedPackage {
             friend: Friend {
@@ -191,12 +817,15 @@ impl Client {
         })
     }
 
-    pub fn create_group(&mut self) -> Result<String, JsError> {
+    /// Creates a group in which we appear with the user name to the friends we invite
+    pub fn create_group(&mut self, user_name: Option<String>) -> Result<String, JsError> {
         let group = MlsGroup::builder()
             .use_ratchet_tree_extension(true)
-            // //TODO should we enforce usage of application id in the capabilities?
-            // .with_leaf_node_extensions(encode_application_id(self.id.clone(), &self.user.name))
-            // .unwrap()
+            // Accept public commits and proposals while still encrypting our own handshake messages
+            .with_wire_format_policy(MIXED_CIPHERTEXT_WIRE_FORMAT_POLICY)
+            // Invited friends read who invited them from our leaf node in the ratchet tree included in the welcome
+            .with_capabilities(leaf_node_capabilities())
+            .with_leaf_node_extensions(encode_leaf_node_application_id(&self.id, &user_name))?
             .build(
                 &self.provider,
                 &self.user.signature_key,
@@ -204,101 +833,570 @@ impl Client {
             )?;
 
         let group_id = group.group_id();
-        if self.groups.contains(&group_id) {
+        if self.groups.contains_key(group_id) {
             return Err(CreateGroupError::IdCollision.into());
         }
         let js_group_id = BASE64_URL_SAFE_NO_PAD.encode(group_id.as_slice());
 
-        self.groups.insert(group_id.clone());
+        self.groups.insert(group_id.clone(), GroupState::new());
         Ok(js_group_id)
     }
 
-    /// Creates a group using the package decoded in when reading the invite.
-    /// Returns the serialized welcome message.
+    /// Adds the friend from the package decoded when reading the invite to the group.
+    /// Returns the serialized welcome message for the friend and the commit for the existing members.
     /// Don't use "package" as a parameter name as it is reserved in JavaScript and will make
     /// the wasm bindgen code fail.
+    ///
+    /// If the friend protected the invite with a passphrase, the same passphrase has to be provided.
+    /// The passphrase is turned into a pre-shared key that every member needs to process the commit.
+    /// As only we and the invited friend know it, protected invites can only be used in groups without other members.
     pub fn invite(
         &mut self,
         group_id: &str,
         key_package: DecodedPackage,
-        user_name: Option<String>,
-    ) -> Result<Vec<u8>, JsError> {
+        passphrase: Option<String>,
+    ) -> Result<Invitation, JsError> {
         let bytes = BASE64_URL_SAFE_NO_PAD.decode(group_id)?;
         let group_id = GroupId::from_slice(&bytes);
         let package = key_package.key_package;
+        // The friend decoded in JavaScript could have been changed, the credential is what the key package signs
+        let friend = friend_from_leaf_node(package.leaf_node())?;
+        let signature_key = package.leaf_node().signature_key().as_slice().to_vec();
         let storage = self.provider.storage();
 
-        let mut group = MlsGroup::load(storage, &group_id)?.ok_or_else(|| GroupNotFound)?;
+        let mut group = MlsGroup::load(storage, &group_id)?.ok_or(GroupNotFound)?;
+        // Only we are in the group when it was just created
+        let has_other_members = group.members().count() > 1;
+
+        let psk_id = match passphrase {
+            Some(_) if has_other_members => return Err(PassphraseWithOtherMembers.into()),
+            Some(passphrase) => {
+                let reference = package.hash_ref(self.provider.crypto())?;
+                let psk_id = self.store_invite_psk(&reference, &passphrase)?;
+                // The proposal is committed together with the add and does not need to be sent separately
+                if let Err(error) = group.propose_external_psk_by_value(
+                    &self.provider,
+                    &self.user.signature_key,
+                    psk_id.clone(),
+                ) {
+                    self.provider.storage().delete_psk(psk_id.psk())?;
+                    return Err(error.into());
+                }
+                Some(psk_id)
+            }
+            None => None,
+        };
 
-        //TODO support multi user groups
-        // We don't need the out message because there is no other group members
-        // that need to be "informed" of the change (the commit message)
-        let (_out_message, welcome, _group_info) =
-            group.add_members(&self.provider, &self.user.signature_key, &[package])?;
+        let (commit, welcome, _group_info) =
+            match group.add_members(&self.provider, &self.user.signature_key, &[package]) {
+                Ok(added) => added,
+                Err(error) => {
+                    // The queued proposal would keep the group from sending messages until the next commit.
+                    // Only we are in groups with a passphrase so there are no proposals of others to keep.
+                    if let Some(psk_id) = &psk_id {
+                        group.clear_pending_proposals(self.provider.storage())?;
+                        self.provider.storage().delete_psk(psk_id.psk())?;
+                    }
+                    return Err(error.into());
+                }
+            };
 
         // Process it on our end
         group.merge_pending_commit(&self.provider)?;
-        // Create introduction message as welcome does not include enough information that are needed on the application layer
-        let introduction = ApplicationMessage::Introduction {
-            id: self.id.to_string(),
-            user_name,
+        // The pre-shared key is only needed for the epoch the friend joins in
+        if let Some(psk_id) = psk_id {
+            self.provider.storage().delete_psk(psk_id.psk())?;
+        }
+        // Adding members includes a path which updates our leaf
+        if let Some(state) = self.groups.get_mut(&group_id) {
+            state.record_rotation();
+        }
+        let key_changed = self.contacts.record(&friend, &signature_key, &group_id);
+
+        // The welcome introduces us through our leaf node so it can be sent on its own
+        // The u16 describes the length of the message e.g. u8 would be max length 255
+        let welcome = TlsSliceU16(&[welcome]).tls_serialize_detached()?;
+
+        let commit = if has_other_members {
+            Some(TlsSliceU16(&[commit]).tls_serialize_detached()?)
+        } else {
+            None
         };
 
-        let data = postcard::to_allocvec(&introduction)?;
-        let message = group.create_message(&self.provider, &self.user.signature_key, &data)?;
+        Ok(Invitation {
+            welcome,
+            commit,
+            key_changed,
+        })
+    }
 
-        //TODO the introduction has to be sent to all group members when we support multi user groups
-        // Batch send messages
-        //TODO test without vector and U8 slice variant
-        let mut vector = Vec::new();
-        vector.push(welcome);
-        vector.push(message);
-        // The u16 describes the length of the message e.g. u8 would be max length 255
-        Ok(TlsSliceU16(&vector).tls_serialize_detached()?)
+    /// Creates a fresh key package to send to a friend of a group we lost our state of.
+    /// The friend answers with a welcome to a new group that replaces the lost group.
+    /// Returns the serialized key package to send to the friend.
+    pub fn request_recovery(
+        &mut self,
+        group_id: &str,
+        user_name: Option<String>,
+    ) -> Result<Box<[u8]>, JsError> {
+        let group_id = BASE64_URL_SAFE_NO_PAD.decode(group_id)?;
+        let group_id = GroupId::from_slice(&group_id);
+
+        let key_package = self.build_key_package(&user_name, false)?;
+        let reference = self.key_packages.insert(
+            self.provider.crypto(),
+            key_package.clone(),
+            user_name,
+            true,
+            self.invite_lifetime_seconds,
+        )?;
+        self.key_packages.link_recovery(&reference, group_id);
+
+        let message = MlsMessageOut::from(key_package);
+        Ok(TlsSliceU16(&[message])
+            .tls_serialize_detached()?
+            .into_boxed_slice())
+    }
+
+    /// Answers a [`Message::RecoveryRequested`] by creating a new group with the friend that replaces the lost group.
+    /// Returns the id of the new group and the welcome to send to the friend.
+    /// Other members of the lost group have to be invited to the new group again.
+    pub fn recover_group(
+        &mut self,
+        group_id: &str,
+        key_package: DecodedPackage,
+        user_name: Option<String>,
+    ) -> Result<Recovery, JsError> {
+        let replaced = BASE64_URL_SAFE_NO_PAD.decode(group_id)?;
+        let replaced = GroupId::from_slice(&replaced);
+
+        let new_group_id = self.create_group(user_name)?;
+        let invitation = self.invite(&new_group_id, key_package, None)?;
+
+        let bytes = BASE64_URL_SAFE_NO_PAD.decode(&new_group_id)?;
+        if let Some(state) = self.groups.get_mut(&GroupId::from_slice(&bytes)) {
+            state.replaces = Some(replaced);
+        }
+
+        Ok(Recovery {
+            group_id: new_group_id,
+            welcome: invitation.welcome,
+        })
+    }
+
+    /// The group that the group was created to replace, if any
+    pub fn replaced_group(&self, group_id: &str) -> Result<Option<String>, JsError> {
+        let group_id = BASE64_URL_SAFE_NO_PAD.decode(group_id)?;
+        let state = self
+            .groups
+            .get(&GroupId::from_slice(&group_id))
+            .ok_or(GroupNotFound)?;
+
+        Ok(state
+            .replaces
+            .as_ref()
+            .map(|group_id| BASE64_URL_SAFE_NO_PAD.encode(group_id.as_slice())))
     }
 
     fn process_private_message(
         &mut self,
         message: PrivateMessageIn,
-    ) -> Result<Message, ProcessPrivateMessageError> {
+    ) -> Result<Message, ProcessProtocolMessageError> {
+        let data = message.tls_serialize_detached()?;
+        let hash = self.provider.crypto().hash(HashType::Sha2_256, &data)?;
         let message = ProtocolMessage::from(message);
-        let mut group = MlsGroup::load(self.provider.storage(), message.group_id())?
-            .ok_or_else(|| ProcessPrivateMessageError::GroupNotFound)?;
+        let group_id = message.group_id().clone();
+        let js_group_id = BASE64_URL_SAFE_NO_PAD.encode(group_id.as_slice());
+        if self
+            .groups
+            .get(&group_id)
+            .is_some_and(|state| state.seen.contains_ciphertext(&hash))
+        {
+            return Ok(Message::Duplicate {
+                group_id: js_group_id,
+                id: None,
+            });
+        }
 
-        let message = group.process_message(&self.provider, message)?;
-        let content = match message.into_content() {
-            ProcessedMessageContent::ApplicationMessage(content) => content,
-            other => return Err(ProcessPrivateMessageError::UnexpectedMessageContent(other)),
+        let epoch = MlsGroup::load(self.provider.storage(), &group_id)?.map(|group| group.epoch());
+        if let Some(epoch) = epoch
+            && message.epoch() > epoch
+            && let Some(state) = self.groups.get_mut(&group_id)
+        {
+            state.pending.push(message.epoch().as_u64(), data);
+            return Ok(Message::Pending {
+                group_id: js_group_id,
+            });
+        }
+
+        let processed = match self.process_protocol_message(message) {
+            Ok(processed) => processed,
+            Err(error) => return self.record_failure(group_id, error),
         };
+        self.unknown_groups.remove(&group_id);
+        // Remembered only once processed so a message that failed can still be processed when delivered again
+        if let Some(state) = self.groups.get_mut(&group_id) {
+            state.seen.insert_ciphertext(hash);
+            state.failures = 0;
+        }
 
-        let content = postcard::from_bytes(&content.into_bytes())?;
-        let js_group_id = BASE64_URL_SAFE_NO_PAD.encode(group.group_id().as_slice());
+        Ok(processed)
+    }
 
-        Ok(Message::Private {
-            group_id: js_group_id,
-            content,
+    /// Counts the messages of the group that failed to be processed in a row.
+    /// Only failures that suggest our state of the group is lost or outdated are counted, like not knowing the group
+    /// or not being able to decrypt the message. Once there are too many, the failure is reported as
+    /// [`Message::Desynced`] instead.
+    fn record_failure(
+        &mut self,
+        group_id: GroupId,
+        error: ProcessProtocolMessageError,
+    ) -> Result<Message, ProcessProtocolMessageError> {
+        if !matches!(
+            error,
+            ProcessProtocolMessageError::GroupNotFound
+                | ProcessProtocolMessageError::ProcessMessage(_)
+        ) {
+            return Err(error);
+        }
+
+        match self.count_failures(&group_id, 1) {
+            Some(desynced) => Ok(desynced),
+            None => Err(error),
+        }
+    }
+
+    /// Adds to the messages of the group that failed to be processed in a row.
+    /// Returns [`Message::Desynced`] when this makes the failures reach the threshold. It is reported once so the app
+    /// does not ask for recovery with every further message.
+    fn count_failures(&mut self, group_id: &GroupId, count: u32) -> Option<Message> {
+        let failures = match self.groups.get_mut(group_id) {
+            Some(state) => &mut state.failures,
+            None if self.unknown_groups.len() < MAX_UNKNOWN_GROUPS
+                || self.unknown_groups.contains_key(group_id) =>
+            {
+                self.unknown_groups.entry(group_id.clone()).or_default()
+            }
+            None => return None,
+        };
+
+        let before = *failures;
+        *failures = failures.saturating_add(count);
+        (before < DESYNC_THRESHOLD && *failures >= DESYNC_THRESHOLD).then(|| Message::Desynced {
+            group_id: BASE64_URL_SAFE_NO_PAD.encode(group_id.as_slice()),
+            failures: *failures,
         })
     }
 
+    fn process_public_message(
+        &mut self,
+        message: PublicMessageIn,
+    ) -> Result<Message, ProcessProtocolMessageError> {
+        self.process_protocol_message(ProtocolMessage::from(message))
+    }
+
+    /// Processes private and public messages that are sent in the context of a group.
+    /// Proposals are stored until a commit including them arrives and commits are merged right away.
+    fn process_protocol_message(
+        &mut self,
+        message: ProtocolMessage,
+    ) -> Result<Message, ProcessProtocolMessageError> {
+        let mut group = MlsGroup::load(self.provider.storage(), message.group_id())?
+            .ok_or(ProcessProtocolMessageError::GroupNotFound)?;
+
+        let message = group.process_message(&self.provider, message)?;
+        let js_group_id = BASE64_URL_SAFE_NO_PAD.encode(group.group_id().as_slice());
+        let sender = friend_from_credential(message.credential().clone())?;
+        match message.into_content() {
+            ProcessedMessageContent::ApplicationMessage(content) => {
+                if let Some(state) = self.groups.get_mut(group.group_id()) {
+                    state.record_message();
+                }
+                // Processing still advanced the ratchet so later messages from other members can be decrypted
+                if self.contacts.is_blocked(&sender.id) {
+                    return Ok(Message::Blocked {
+                        group_id: js_group_id,
+                        sender,
+                    });
+                }
+
+                let message = decode_content(js_group_id, sender, &content.into_bytes())?;
+                // The sender might have encrypted the message again, for example when retrying to send it
+                if let Some((group_id, id)) = message_id(&message)
+                    && let Some(state) = self.groups.get_mut(group.group_id())
+                    && !state.seen.insert_id(id)
+                {
+                    return Ok(Message::Duplicate {
+                        group_id: group_id.to_owned(),
+                        id: Some(id.to_owned()),
+                    });
+                }
+
+                Ok(message)
+            }
+            ProcessedMessageContent::ProposalMessage(proposal)
+            | ProcessedMessageContent::ExternalJoinProposalMessage(proposal) => {
+                // A member proposing their own removal is leaving the group
+                if let (Proposal::Remove(remove), Sender::Member(index)) =
+                    (proposal.proposal(), proposal.sender())
+                    && remove.removed() == *index
+                {
+                    group.store_pending_proposal(self.provider.storage(), *proposal)?;
+                    return Ok(Message::Left {
+                        group_id: js_group_id,
+                        friend: sender,
+                    });
+                }
+
+                let change = match proposal.proposal() {
+                    Proposal::Add(add) => ProposedChange::Add {
+                        friend: friend_from_leaf_node(add.key_package().leaf_node())?,
+                    },
+                    Proposal::Remove(remove) => ProposedChange::Remove {
+                        friend: member_friend(&group, remove.removed())?,
+                    },
+                    Proposal::Update(_) => ProposedChange::Update,
+                    _ => ProposedChange::Other,
+                };
+
+                group.store_pending_proposal(self.provider.storage(), *proposal)?;
+                Ok(Message::Proposal {
+                    group_id: js_group_id,
+                    sender,
+                    change,
+                })
+            }
+            ProcessedMessageContent::StagedCommitMessage(commit) => {
+                if commit.self_removed() {
+                    group.merge_staged_commit(&self.provider, *commit)?;
+                    group.delete(self.provider.storage())?;
+                    self.groups.remove(group.group_id());
+                    self.contacts.remove_group(group.group_id());
+                    return Ok(Message::Removed {
+                        group_id: js_group_id,
+                        by: sender,
+                    });
+                }
+
+                let added_leaf_nodes = commit
+                    .add_proposals()
+                    .map(|proposal| proposal.add_proposal().key_package().leaf_node().clone())
+                    .collect::<Vec<_>>();
+                let added = added_leaf_nodes
+                    .iter()
+                    .map(friend_from_leaf_node)
+                    .collect::<Result<_, _>>()?;
+
+                // Removed members have to be looked up before merging as they are no longer in the group afterwards
+                let removed: Vec<Friend> = commit
+                    .remove_proposals()
+                    .map(|proposal| member_friend(&group, proposal.remove_proposal().removed()))
+                    .collect::<Result<_, _>>()?;
+                for friend in &removed {
+                    self.contacts.remove_member(&friend.id, group.group_id());
+                }
+
+                let mut updated_leaf_nodes = commit
+                    .update_proposals()
+                    .map(|proposal| proposal.update_proposal().leaf_node().clone())
+                    .collect::<Vec<_>>();
+                // The committer rotated their keys if the commit includes a path
+                if let Some(leaf_node) = commit.update_path_leaf_node() {
+                    updated_leaf_nodes.push(leaf_node.clone());
+                }
+                let updated = updated_leaf_nodes
+                    .iter()
+                    .map(friend_from_leaf_node)
+                    .collect::<Result<_, _>>()?;
+
+                let key_changed = self.record_contacts(
+                    group.group_id(),
+                    added_leaf_nodes.iter().chain(&updated_leaf_nodes),
+                )?;
+
+                group.merge_staged_commit(&self.provider, *commit)?;
+
+                Ok(Message::Commit {
+                    group_id: js_group_id,
+                    added,
+                    removed,
+                    updated,
+                    key_changed,
+                })
+            }
+        }
+    }
+
+    /// Records the friends of the leaf nodes in the contact book.
+    /// Returns the friends that use a different signature key than before.
+    fn record_contacts<'a>(
+        &mut self,
+        group_id: &GroupId,
+        leaf_nodes: impl Iterator<Item = &'a LeafNode>,
+    ) -> Result<Vec<Friend>, ReadCredentialError> {
+        let mut changed = Vec::new();
+        for leaf_node in leaf_nodes {
+            let friend = friend_from_leaf_node(leaf_node)?;
+            if self
+                .contacts
+                .record(&friend, leaf_node.signature_key().as_slice(), group_id)
+            {
+                changed.push(friend);
+            }
+        }
+
+        Ok(changed)
+    }
+
     fn process_welcome(
         &mut self,
         welcome: Welcome,
-        mut rest: IntoIter<MlsMessageIn>,
+        rest: &mut IntoIter<MlsMessageIn>,
     ) -> Result<Message, ProcessWelcomeMessageError> {
         // Step 1: Process welcome
         let configuration = MlsGroupJoinConfig::builder()
             .use_ratchet_tree_extension(true)
+            .wire_format_policy(MIXED_CIPHERTEXT_WIRE_FORMAT_POLICY)
             .build();
 
-        let welcome: StagedWelcome =
-            StagedWelcome::new_from_welcome(&self.provider, &configuration, welcome, None)?;
+        let consumed: Vec<KeyPackageRef> = welcome
+            .secrets()
+            .iter()
+            .map(|secrets| secrets.new_member())
+            .collect();
+
+        // Fail with a clear error instead of OpenMLS not finding the key package in the storage
+        let invite = self
+            .key_packages
+            .find(&consumed)
+            .ok_or(ProcessWelcomeMessageError::InviteNotFound)?;
+        let invite_id = encode_invite_id(&invite.reference);
+        let invite_label = invite.user_name.clone();
+        let replaces = invite.recovers.clone();
+        let psk = invite
+            .passphrase_protected
+            .then(|| invite_psk(&invite.reference));
+
+        let welcome = ProcessedWelcome::new_from_welcome(&self.provider, &configuration, welcome)
+            .map_err(|error| match error {
+            // The key schedule includes the pre-shared key so a different passphrase fails decryption
+            WelcomeError::GroupInfo(GroupInfoError::DecryptionFailed) if psk.is_some() => {
+                ProcessWelcomeMessageError::PassphraseMismatch
+            }
+            error => error.into(),
+        })?;
+
+        // Without the pre-shared key the inviter did not prove knowing the passphrase
+        if let Some(psk) = &psk
+            && !welcome.psks().iter().any(|psk_id| psk_id.psk() == psk)
+        {
+            return Err(ProcessWelcomeMessageError::PassphraseMissing);
+        }
+
+        let welcome = welcome.into_staged_welcome(&self.provider, None)?;
+        self.key_packages.remove_consumed(&consumed);
+        if let Some(psk) = &psk {
+            self.provider.storage().delete_psk(psk)?;
+        }
+
+        // Step 2: Read who invited us from their leaf node
+        let inviter = welcome.welcome_sender()?;
+        let has_application_id = leaf_node_application_id(inviter).is_some();
+        let friend = friend_from_leaf_node(inviter)?;
+
+        // The id is part of the credential so older clients can be rejected before processing their introduction
+        if self.contacts.is_blocked(&friend.id) {
+            if !has_application_id {
+                rest.next();
+            }
+            return Err(ProcessWelcomeMessageError::SenderBlocked(friend.id));
+        }
 
         let mut group = welcome.into_group(&self.provider)?;
 
-        // Step 2: Process introduction
+        // Clients that did not include their id in the leaf node follow up the welcome with an introduction
+        let friend = if has_application_id {
+            friend
+        } else {
+            self.process_introduction(&mut group, rest)?
+        };
+
+        // The inviter is recorded last to also remember the name they appear with
+        for member in group.members() {
+            let member_friend = friend_from_credential(member.credential)?;
+            if *member_friend.id != *self.id && member_friend.id != friend.id {
+                self.contacts
+                    .record(&member_friend, &member.signature_key, group.group_id());
+            }
+        }
+        let inviter_key = find_member(&group, &friend.id)
+            .map(|member| member.signature_key)
+            .unwrap_or_default();
+        self.contacts
+            .record(&friend, &inviter_key, group.group_id());
+
+        let js_group_id = BASE64_URL_SAFE_NO_PAD.encode(group.group_id().as_slice());
+        let mut state = GroupState::new();
+        if let Some(replaced) = &replaces {
+            self.unknown_groups.remove(replaced);
+            if let Some(replaced_state) = self.groups.get_mut(replaced) {
+                replaced_state.failures = 0;
+            }
+        }
+        state.replaces = replaces.clone();
+        self.groups.insert(group.group_id().clone(), state);
+
+        Ok(Message::Welcome {
+            friend,
+            group_id: js_group_id,
+            invite_id: Some(invite_id),
+            invite_label,
+            replaces: replaces.map(|group_id| BASE64_URL_SAFE_NO_PAD.encode(group_id.as_slice())),
+        })
+    }
+
+    /// Processes a fresh key package a friend sent after losing their state of a group we share with them
+    fn process_recovery_request(
+        &self,
+        key_package: KeyPackageIn,
+    ) -> Result<Message, ProcessRecoveryRequestError> {
+        let key_package = self.validate_key_package(key_package)?;
+        let friend_id = &key_package.friend.id;
+        if self.contacts.is_blocked(friend_id) {
+            return Err(ProcessRecoveryRequestError::SenderBlocked(
+                friend_id.clone(),
+            ));
+        }
+
+        let groups = self
+            .contacts
+            .get(friend_id)
+            .map(|contact| contact.groups)
+            .unwrap_or_default();
+        let unknown_key = !self.contacts.knows_key(
+            friend_id,
+            key_package
+                .key_package
+                .leaf_node()
+                .signature_key()
+                .as_slice(),
+        );
+
+        Ok(Message::RecoveryRequested {
+            key_package,
+            groups,
+            unknown_key,
+        })
+    }
+
+    /// Processes the introduction that older clients sent after the welcome before the leaf node included their id
+    fn process_introduction(
+        &self,
+        group: &mut MlsGroup,
+        rest: &mut IntoIter<MlsMessageIn>,
+    ) -> Result<Friend, ProcessWelcomeMessageError> {
         let introduction =
             rest.next()
-                .ok_or_else(|| ProcessWelcomeMessageError::UnexpectedMessageCount {
+                .ok_or(ProcessWelcomeMessageError::UnexpectedMessageCount {
                     expected: 2,
                     actual: 1,
                 })?;
@@ -307,13 +1405,14 @@ impl Client {
             MlsMessageBodyIn::PrivateMessage(introduction) => introduction,
             other => {
                 return Err(ProcessWelcomeMessageError::UnexpectedIntroductionBody(
-                    other,
+                    Box::new(other),
                 ));
             }
         };
 
         // Extract introduction application message with new group now
         let introduction = group.process_message(&self.provider, introduction)?;
+        let sender = friend_from_credential(introduction.credential().clone())?;
         let content = match introduction.into_content() {
             ProcessedMessageContent::ApplicationMessage(content) => content,
             other => {
@@ -326,55 +1425,510 @@ impl Client {
             user_name: name,
         } = postcard::from_bytes(&content.into_bytes())?;
 
-        let js_group_id = BASE64_URL_SAFE_NO_PAD.encode(group.group_id().as_slice());
-        self.groups.insert(group.group_id().clone());
+        // The introduction is only authenticated by the credential of the sender, not the id it claims
+        if id != sender.id {
+            return Err(ProcessWelcomeMessageError::IntroductionIdMismatch {
+                claimed: id,
+                actual: sender.id,
+            });
+        }
 
-        Ok(Message::Welcome {
-            friend: Friend { id, name },
-            group_id: js_group_id,
-        })
+        Ok(Friend { id, name })
     }
 
-    pub fn process_message(&mut self, data: &[u8]) -> Result<Message, JsError> {
+    /// Processes every message in the batch.
+    /// A message that fails to process is reported as [`Message::Failed`] and does not stop the rest of the batch
+    /// from being processed.
+    pub fn process_message(&mut self, data: &[u8]) -> Result<Vec<Message>, JsError> {
         // Have to use TlsVecU16 because the bit length (16) stands for the space reserved to encode the length of the message not the integer stored like in a Vec<u8>
         let mut messages = TlsVecU16::<MlsMessageIn>::tls_deserialize_exact_bytes(data)?
             .into_vec()
             .into_iter();
 
-        let message = messages
-            .next()
-            .ok_or_else(|| JsError::new("Payload deserialized but there was no message"))?;
+        let count = messages.len();
+        if count == 0 {
+            return Err(JsError::new(
+                "Payload deserialized but there was no message",
+            ));
+        }
+
+        let mut processed = Vec::with_capacity(count);
+        while let Some(message) = messages.next() {
+            // The welcome consumes the introduction following it so the index can not be counted with enumerate
+            let index = count - messages.len() - 1;
+            let result = self.process_batch_item(message, &mut messages);
+            processed.push(result.unwrap_or_else(|error| Message::Failed {
+                index,
+                error: error.to_string(),
+            }));
+            self.process_pending(index, &mut processed);
+        }
 
-        let value = match message.extract() {
+        let acknowledgements = self.acknowledge(&processed);
+        processed.extend(acknowledgements);
+        Ok(processed)
+    }
+
+    /// Processes the messages that waited for the epoch their group is in now.
+    /// Failures are reported with the index of the message in the batch that made them processable.
+    fn process_pending(&mut self, index: usize, processed: &mut Vec<Message>) {
+        // Messages that are processed might be commits that make further messages processable
+        loop {
+            let waiting: Vec<GroupId> = self
+                .groups
+                .iter()
+                .filter(|(_, state)| !state.pending.is_empty())
+                .map(|(group_id, _)| group_id.clone())
+                .collect();
+
+            let mut progressed = false;
+            for group_id in waiting {
+                let Ok(Some(group)) = MlsGroup::load(self.provider.storage(), &group_id) else {
+                    continue;
+                };
+                let Some(state) = self.groups.get_mut(&group_id) else {
+                    continue;
+                };
+
+                let expired = state.pending.take_expired();
+                let ready = state.pending.take_ready(group.epoch().as_u64());
+                if expired > 0 {
+                    processed.push(Message::Expired {
+                        group_id: BASE64_URL_SAFE_NO_PAD.encode(group_id.as_slice()),
+                        count: expired,
+                    });
+                    // Dropped messages are lost just like messages that failed, so they count towards a desync
+                    processed.extend(self.count_failures(&group_id, expired));
+                }
+
+                for data in ready {
+                    progressed = true;
+                    let result = PrivateMessageIn::tls_deserialize_exact_bytes(&data)
+                        .map_err(ProcessProtocolMessageError::from)
+                        .and_then(|message| self.process_private_message(message));
+                    processed.push(result.unwrap_or_else(|error| Message::Failed {
+                        index,
+                        error: error.to_string(),
+                    }));
+                }
+            }
+
+            if !progressed {
+                return;
+            }
+        }
+    }
+
+    /// Creates a delivery receipt for each group that we received messages with an id in.
+    /// Receipts are best effort, so a receipt that fails to be created is left out instead of failing the batch.
+    fn acknowledge(&mut self, processed: &[Message]) -> Vec<Message> {
+        let mut received: Vec<(&str, Vec<String>)> = Vec::new();
+        for (group_id, id) in processed.iter().filter_map(message_id) {
+            match received.iter_mut().find(|(group, _)| *group == group_id) {
+                Some((_, ids)) => ids.push(id.to_owned()),
+                None => received.push((group_id, vec![id.to_owned()])),
+            }
+        }
+
+        received
+            .into_iter()
+            .filter_map(|(group_id, ids)| {
+                let receipt = encode_receipt(ReceiptKind::Delivered, ids).ok()?;
+                let payload = self.create_messages(group_id, vec![receipt], false).ok()?;
+                Some(Message::Acknowledgement {
+                    group_id: group_id.to_owned(),
+                    payload: payload.into_vec(),
+                })
+            })
+            .collect()
+    }
+
+    fn process_batch_item(
+        &mut self,
+        message: MlsMessageIn,
+        rest: &mut IntoIter<MlsMessageIn>,
+    ) -> Result<Message, ProcessBatchItemError> {
+        let message = match message.extract() {
             MlsMessageBodyIn::PrivateMessage(message) => self.process_private_message(message)?,
-            MlsMessageBodyIn::Welcome(welcome) => self.process_welcome(welcome, messages)?,
-            MlsMessageBodyIn::PublicMessage(_public_message_in) => todo!("Public message in"),
-            MlsMessageBodyIn::GroupInfo(_verifiable_group_info) => todo!("Group info in"),
-            MlsMessageBodyIn::KeyPackage(_key_package_in) => todo!("key package in"),
+            MlsMessageBodyIn::Welcome(welcome) => self.process_welcome(welcome, rest)?,
+            MlsMessageBodyIn::PublicMessage(message) => self.process_public_message(message)?,
+            MlsMessageBodyIn::GroupInfo(_) => return Err(UnexpectedMessageBody::GroupInfo.into()),
+            MlsMessageBodyIn::KeyPackage(key_package) => {
+                self.process_recovery_request(key_package)?
+            }
         };
 
-        Ok(value)
+        Ok(message)
     }
 
     pub fn send_message(
         &mut self,
         group_id: &str,
         message: MessageContent,
+    ) -> Result<SentMessage, JsError> {
+        let SentMessages { mut ids, payload } = self.send_messages(group_id, vec![message])?;
+        Ok(SentMessage {
+            id: ids.remove(0),
+            payload,
+        })
+    }
+
+    /// Creates a MLS message for each content and batches them into one payload.
+    /// Allows flushing messages that were queued while offline with a single request to the delivery service.
+    pub fn send_messages(
+        &mut self,
+        group_id: &str,
+        messages: Vec<MessageContent>,
+    ) -> Result<SentMessages, JsError> {
+        if messages.is_empty() {
+            return Err(EmptyBatch.into());
+        }
+
+        let (ids, contents) = messages
+            .into_iter()
+            .map(|message| {
+                let content = Content::Text { text: message.text };
+                encode_content(message.sent_at, content)
+            })
+            .collect::<Result<(Vec<_>, Vec<_>), postcard::Error>>()?;
+
+        let payload = self.create_messages(group_id, contents, false)?;
+        Ok(SentMessages {
+            ids,
+            payload: payload.into_vec(),
+        })
+    }
+
+    /// Sends a text message, reply, edit, deletion or reaction.
+    /// Returns the id of the message to refer to it later together with the payload for the delivery service.
+    pub fn send(
+        &mut self,
+        group_id: &str,
+        message: OutgoingMessage,
+    ) -> Result<SentMessage, JsError> {
+        let (id, content) = encode_content(message.sent_at, message.content)?;
+        let payload = self.create_messages(group_id, vec![content], false)?;
+
+        Ok(SentMessage {
+            id,
+            payload: payload.into_vec(),
+        })
+    }
+
+    /// Tells the members of the group that we read the messages with the ids.
+    /// Returns nothing if read receipts are turned off for the group.
+    pub fn send_read_receipt(
+        &mut self,
+        group_id: &str,
+        ids: Vec<String>,
+    ) -> Result<Option<Box<[u8]>>, JsError> {
+        if !self.read_receipts(group_id)? {
+            return Ok(None);
+        }
+
+        let receipt = encode_receipt(ReceiptKind::Read, ids)?;
+        Ok(Some(self.create_messages(
+            group_id,
+            vec![receipt],
+            false,
+        )?))
+    }
+
+    pub fn read_receipts(&self, group_id: &str) -> Result<bool, JsError> {
+        let group_id = GroupId::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(group_id)?);
+        let state = self.groups.get(&group_id).ok_or(GroupNotFound)?;
+        Ok(state.read_receipts)
+    }
+
+    /// Turns read receipts for the group on or off.
+    /// Delivery receipts are always sent as they only tell that the message arrived.
+    pub fn set_read_receipts(&mut self, group_id: &str, enabled: bool) -> Result<(), JsError> {
+        let group_id = GroupId::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(group_id)?);
+        let state = self.groups.get_mut(&group_id).ok_or(GroupNotFound)?;
+        state.read_receipts = enabled;
+        Ok(())
+    }
+
+    /// Encrypts the file with a new key.
+    /// The ciphertext has to be transferred separately while the returned attachment is sent to the group.
+    pub fn encrypt_attachment(
+        &self,
+        data: &[u8],
+        mime_type: String,
+    ) -> Result<EncryptedAttachment, JsError> {
+        let (ciphertext, attachment) = encrypt_attachment(self.provider.crypto(), data, mime_type)?;
+        Ok(EncryptedAttachment {
+            ciphertext,
+            attachment,
+        })
+    }
+
+    /// Verifies and decrypts the whole ciphertext of the attachment
+    pub fn decrypt_attachment(
+        &self,
+        attachment: Attachment,
+        ciphertext: &[u8],
+    ) -> Result<Box<[u8]>, JsError> {
+        let data = decrypt_attachment(self.provider.crypto(), &attachment, ciphertext)?;
+        Ok(data.into_boxed_slice())
+    }
+
+    /// Decrypts one chunk of the attachment, for example while it is still downloading.
+    /// The chunk at `index` starts at `index * (chunk_size + 16)` in the ciphertext.
+    pub fn decrypt_attachment_chunk(
+        &self,
+        attachment: Attachment,
+        index: u32,
+        chunk: &[u8],
+    ) -> Result<Box<[u8]>, JsError> {
+        let data =
+            decrypt_attachment_chunk(self.provider.crypto(), &attachment, index.into(), chunk)?;
+        Ok(data.into_boxed_slice())
+    }
+
+    /// Tells the members of the group that we started or stopped typing.
+    /// The payload is marked as ephemeral so the delivery service does not keep it for offline members.
+    pub fn send_typing(&mut self, group_id: &str, typing: bool) -> Result<Box<[u8]>, JsError> {
+        let content = encode_typing(typing)?;
+        self.create_messages(group_id, vec![content], true)
+    }
+
+    /// Encrypts the encoded contents for the group and batches them into one payload
+    fn create_messages(
+        &mut self,
+        group_id: &str,
+        contents: Vec<Vec<u8>>,
+        ephemeral: bool,
     ) -> Result<Box<[u8]>, JsError> {
         let group_id = BASE64_URL_SAFE_NO_PAD.decode(group_id)?;
         let group_id = GroupId::from_slice(&group_id);
-        let mut group =
-            MlsGroup::load(self.provider.storage(), &group_id)?.ok_or_else(|| GroupNotFound)?;
+        let mut group = MlsGroup::load(self.provider.storage(), &group_id)?.ok_or(GroupNotFound)?;
 
-        let message = postcard::to_allocvec(&message)?;
-        let message = group.create_message(&self.provider, &self.user.signature_key, &message)?;
+        let messages = contents
+            .iter()
+            .map(|message| {
+                // Authenticated data is reset after each message
+                if ephemeral {
+                    group.set_aad(EPHEMERAL_AAD.to_vec());
+                }
+                let message =
+                    group.create_message(&self.provider, &self.user.signature_key, message)?;
+                if let Some(state) = self.groups.get_mut(&group_id) {
+                    state.record_message();
+                }
 
-        // We can batch send messages so we need to wrap it in a collection
-        let messages = &[message];
-        let message = TlsSliceU16(messages);
-
-        let serialized = message.tls_serialize_detached()?;
+                Ok(message)
+            })
+            .collect::<Result<Vec<_>, JsError>>()?;
 
+        // The u16 describes the length of the batch so it can hold at most 65535 bytes of messages
+        let serialized = TlsSliceU16(&messages).tls_serialize_detached()?;
         Ok(serialized.into_boxed_slice())
     }
+
+    /// Rotates our leaf key in the group with a self-update commit.
+    /// Returns the commit to send to the other members of the group.
+    pub fn rotate_keys(&mut self, group_id: &str) -> Result<Box<[u8]>, JsError> {
+        let group_id = BASE64_URL_SAFE_NO_PAD.decode(group_id)?;
+        let group_id = GroupId::from_slice(&group_id);
+        let mut group = MlsGroup::load(self.provider.storage(), &group_id)?.ok_or(GroupNotFound)?;
+
+        let (commit, _welcome, _group_info) = group.self_update(
+            &self.provider,
+            &self.user.signature_key,
+            LeafNodeParameters::default(),
+        )?;
+        group.merge_pending_commit(&self.provider)?;
+        if let Some(state) = self.groups.get_mut(&group_id) {
+            state.record_rotation();
+        }
+
+        Ok(TlsSliceU16(&[commit])
+            .tls_serialize_detached()?
+            .into_boxed_slice())
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn rotation_policy(&self) -> RotationPolicy {
+        self.rotation_policy
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_rotation_policy(&mut self, policy: RotationPolicy) {
+        self.rotation_policy = policy;
+    }
+
+    /// Returns the ids of the groups in which our leaf key is due to be rotated according to the rotation policy.
+    /// Intended to be checked in the background to then call [`Client::rotate_keys`] for each group.
+    pub fn groups_due_for_rotation(&self) -> Vec<String> {
+        let now = OffsetDateTime::now_utc();
+        self.groups
+            .iter()
+            .filter(|(_, state)| state.is_rotation_due(&self.rotation_policy, now))
+            .map(|(group_id, _)| BASE64_URL_SAFE_NO_PAD.encode(group_id.as_slice()))
+            .collect()
+    }
+
+    /// Derives the safety number from our and the friend's signature key in the group.
+    /// Comparing it with the friend out of band, e.g. in person, confirms that no one is impersonating them.
+    pub fn safety_number(&self, group_id: &str, friend_id: &str) -> Result<String, JsError> {
+        let group_id = BASE64_URL_SAFE_NO_PAD.decode(group_id)?;
+        let group_id = GroupId::from_slice(&group_id);
+        let group = MlsGroup::load(self.provider.storage(), &group_id)?.ok_or(GroupNotFound)?;
+        let member = find_member(&group, friend_id).ok_or(MemberNotFound)?;
+
+        Ok(safety_number(
+            self.provider.crypto(),
+            (&self.id, self.user.credential.signature_key.as_slice()),
+            (friend_id, &member.signature_key),
+        )?)
+    }
+
+    /// Marks the friend as verified after comparing the safety number.
+    /// Stays verified across groups until their signature key changes.
+    pub fn mark_verified(&mut self, group_id: &str, friend_id: &str) -> Result<(), JsError> {
+        let group_id = BASE64_URL_SAFE_NO_PAD.decode(group_id)?;
+        let group_id = GroupId::from_slice(&group_id);
+        let group = MlsGroup::load(self.provider.storage(), &group_id)?.ok_or(GroupNotFound)?;
+        let member = find_member(&group, friend_id).ok_or(MemberNotFound)?;
+
+        self.contacts.verify(friend_id, &member.signature_key);
+        Ok(())
+    }
+
+    /// Returns if the friend was verified before
+    pub fn unmark_verified(&mut self, friend_id: &str) -> bool {
+        self.contacts.unverify(friend_id)
+    }
+
+    pub fn is_verified(&self, friend_id: &str) -> bool {
+        self.contacts.is_verified(friend_id)
+    }
+
+    /// Rejects welcomes from the friend and drops the messages they send in groups we share.
+    /// Their commits and proposals are still processed to keep the groups in sync with the other members.
+    pub fn block(&mut self, friend_id: &str) -> Result<(), JsError> {
+        if friend_id == &*self.id {
+            return Err(BlockSelf.into());
+        }
+
+        self.contacts.block(friend_id);
+        Ok(())
+    }
+
+    /// Returns if the friend was blocked before
+    pub fn unblock(&mut self, friend_id: &str) -> bool {
+        self.contacts.unblock(friend_id)
+    }
+
+    pub fn is_blocked(&self, friend_id: &str) -> bool {
+        self.contacts.is_blocked(friend_id)
+    }
+
+    /// The ids of the blocked friends
+    pub fn blocked(&self) -> Vec<String> {
+        self.contacts.blocked()
+    }
+
+    /// The friends we share at least one group with or shared one with before
+    pub fn contacts(&self) -> Vec<ContactInfo> {
+        self.contacts.list()
+    }
+
+    pub fn contact(&self, friend_id: &str) -> Option<ContactInfo> {
+        self.contacts.get(friend_id)
+    }
+
+    /// The other members of the group that messages and commits have to be sent to
+    pub fn group_members(&self, group_id: &str) -> Result<Vec<Friend>, JsError> {
+        let group_id = BASE64_URL_SAFE_NO_PAD.decode(group_id)?;
+        let group_id = GroupId::from_slice(&group_id);
+        let group = MlsGroup::load(self.provider.storage(), &group_id)?.ok_or(GroupNotFound)?;
+
+        let mut friends = Vec::new();
+        for member in group.members() {
+            let friend = friend_from_credential(member.credential)?;
+            if *friend.id != *self.id {
+                friends.push(friend);
+            }
+        }
+
+        Ok(friends)
+    }
+
+    /// Removes the friend from the group.
+    /// Returns the commit to send to the remaining members and the removed friend.
+    pub fn remove_member(&mut self, group_id: &str, friend_id: &str) -> Result<Box<[u8]>, JsError> {
+        if friend_id == &*self.id {
+            return Err(RemoveSelf.into());
+        }
+
+        let group_id = BASE64_URL_SAFE_NO_PAD.decode(group_id)?;
+        let group_id = GroupId::from_slice(&group_id);
+        let mut group = MlsGroup::load(self.provider.storage(), &group_id)?.ok_or(GroupNotFound)?;
+
+        let index = find_member(&group, friend_id)
+            .map(|member| member.index)
+            .ok_or(MemberNotFound)?;
+
+        let (commit, _welcome, _group_info) =
+            group.remove_members(&self.provider, &self.user.signature_key, &[index])?;
+        group.merge_pending_commit(&self.provider)?;
+        self.contacts.remove_member(friend_id, &group_id);
+        // Removing members includes a path which updates our leaf
+        if let Some(state) = self.groups.get_mut(&group_id) {
+            state.record_rotation();
+        }
+
+        Ok(TlsSliceU16(&[commit])
+            .tls_serialize_detached()?
+            .into_boxed_slice())
+    }
+
+    /// Leaves the group by proposing our own removal which has to be committed by another member.
+    /// The group is deleted when the commit removing us is processed.
+    /// Returns the proposal to send to the other members or nothing if we were the last member and the group was
+    /// deleted right away.
+    pub fn leave_group(&mut self, group_id: &str) -> Result<Option<Box<[u8]>>, JsError> {
+        let group_id = BASE64_URL_SAFE_NO_PAD.decode(group_id)?;
+        let group_id = GroupId::from_slice(&group_id);
+        let mut group = MlsGroup::load(self.provider.storage(), &group_id)?.ok_or(GroupNotFound)?;
+
+        if group.members().count() == 1 {
+            group.delete(self.provider.storage())?;
+            self.groups.remove(&group_id);
+            self.contacts.remove_group(&group_id);
+            return Ok(None);
+        }
+
+        let proposal = group.leave_group(&self.provider, &self.user.signature_key)?;
+        let serialized = TlsSliceU16(&[proposal]).tls_serialize_detached()?;
+        Ok(Some(serialized.into_boxed_slice()))
+    }
+
+    /// Commits the proposals received from other members, like a friend leaving the group.
+    /// Returns the commit to send to all members of the group.
+    pub fn commit_pending_proposals(&mut self, group_id: &str) -> Result<Box<[u8]>, JsError> {
+        let group_id = BASE64_URL_SAFE_NO_PAD.decode(group_id)?;
+        let group_id = GroupId::from_slice(&group_id);
+        let mut group = MlsGroup::load(self.provider.storage(), &group_id)?.ok_or(GroupNotFound)?;
+
+        let (commit, _welcome, _group_info) =
+            group.commit_to_pending_proposals(&self.provider, &self.user.signature_key)?;
+        // Removed members have to be looked up before merging as they are no longer in the group afterwards
+        let removed: Vec<Friend> = group
+            .pending_commit()
+            .into_iter()
+            .flat_map(|commit| commit.remove_proposals())
+            .map(|proposal| member_friend(&group, proposal.remove_proposal().removed()))
+            .collect::<Result<_, _>>()?;
+        group.merge_pending_commit(&self.provider)?;
+        for friend in &removed {
+            self.contacts.remove_member(&friend.id, &group_id);
+        }
+
+        Ok(TlsSliceU16(&[commit])
+            .tls_serialize_detached()?
+            .into_boxed_slice())
+    }
 }
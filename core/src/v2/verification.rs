@@ -0,0 +1,56 @@
+//! Verifying that the signature key of a friend really belongs to them.
+//! Both friends derive the same safety number from their signature keys and compare it out of band, for example by
+//! reading it out loud when meeting in person. The contact book remembers the verified key to notice when it changes.
+
+use openmls_traits::{crypto::OpenMlsCrypto, types::CryptoError, types::HashType};
+
+/// Changes the safety number if the way it is derived ever changes
+const SAFETY_NUMBER_VERSION: u8 = 0;
+/// Slows down finding a key with a similar looking fingerprint
+const FINGERPRINT_ITERATIONS: usize = 5200;
+/// Each fingerprint is shown as 6 groups of 5 digits
+const FINGERPRINT_GROUPS: usize = 6;
+
+/// Hashes the signature key repeatedly into the digits one friend contributes to the safety number
+fn fingerprint(
+    crypto: &impl OpenMlsCrypto,
+    id: &str,
+    signature_key: &[u8],
+) -> Result<String, CryptoError> {
+    let mut hash = crypto.hash(
+        HashType::Sha2_256,
+        &[&[SAFETY_NUMBER_VERSION], signature_key, id.as_bytes()].concat(),
+    )?;
+    for _ in 0..FINGERPRINT_ITERATIONS {
+        hash = crypto.hash(HashType::Sha2_256, &[&hash, signature_key].concat())?;
+    }
+
+    let groups = hash.chunks_exact(5).take(FINGERPRINT_GROUPS).map(|chunk| {
+        let value = chunk
+            .iter()
+            .fold(0u64, |value, byte| (value << 8) | u64::from(*byte));
+        format!("{:05}", value % 100_000)
+    });
+
+    Ok(groups.collect::<Vec<_>>().join(" "))
+}
+
+/// Derives the safety number of two friends from their ids and signature keys.
+/// The fingerprints are ordered by id so both friends see the same number.
+pub(super) fn safety_number(
+    crypto: &impl OpenMlsCrypto,
+    ours: (&str, &[u8]),
+    theirs: (&str, &[u8]),
+) -> Result<String, CryptoError> {
+    let (first, second) = if ours.0 <= theirs.0 {
+        (ours, theirs)
+    } else {
+        (theirs, ours)
+    };
+
+    Ok(format!(
+        "{} {}",
+        fingerprint(crypto, first.0, first.1)?,
+        fingerprint(crypto, second.0, second.1)?
+    ))
+}
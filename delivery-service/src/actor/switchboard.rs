@@ -11,6 +11,8 @@ enum Message {
     Send {
         client_id: Arc<str>,
         message: Arc<[u8]>,
+        /// If the message is only useful while the client is online
+        ephemeral: bool,
         response: oneshot::Sender<Result<(), ClientNotFound>>,
     },
     Connect {
@@ -37,6 +39,7 @@ impl Switchboard {
             Message::Send {
                 client_id,
                 message,
+                ephemeral,
                 response,
             } => {
                 //TODO might need to store the message for the client to receive later
@@ -52,6 +55,15 @@ impl Switchboard {
                 };
 
                 // TODO client is not active. Store message for the client to receive later or send push notification
+                // Until that queue exists every message for an inactive client is dropped and the flag only labels
+                // them. Ephemeral messages have to skip the queue as nobody is interested in a typing indicator once
+                // the client is back online.
+                if ephemeral {
+                    tracing::debug!(
+                        "[Switchboard] Dropping ephemeral message for inactive client {}",
+                        client_id
+                    );
+                }
                 _ = response.send(Ok(()));
             }
             Message::Connect {
@@ -149,12 +161,14 @@ impl Handle {
         &self,
         client_id: Arc<str>,
         message: Arc<[u8]>,
+        ephemeral: bool,
     ) -> Result<(), SendMessageError> {
         let (sender, receiver) = oneshot::channel();
         self.sender
             .send(Message::Send {
                 client_id,
                 message,
+                ephemeral,
                 response: sender,
             })
             .await
@@ -0,0 +1,90 @@
+//! Recognizes messages that are only useful while the recipient is online, like typing indicators.
+//! Clients mark them in the authenticated data of the MLS message, which is the only part of a private message that is
+//! not encrypted. Details: https://www.rfc-editor.org/rfc/rfc9420.html#section-6.3
+
+use tls_codec::{Deserialize, VLBytes};
+
+/// Has to match the marker used by the clients in `meal-core`
+const EPHEMERAL_AAD: &[u8] = b"ephemeral";
+const WIRE_FORMAT_PRIVATE_MESSAGE: u16 = 2;
+
+/// Returns if all MLS messages in the batch are marked as ephemeral.
+/// Anything that can not be read is treated as a regular message.
+pub(crate) fn is_ephemeral(data: &[u8]) -> bool {
+    all_marked(data).unwrap_or(false)
+}
+
+fn all_marked(mut data: &[u8]) -> Result<bool, tls_codec::Error> {
+    // Batches are prefixed with their length in bytes as u16
+    let length = u16::tls_deserialize(&mut data)?;
+    let mut batch = data
+        .get(..usize::from(length))
+        .ok_or(tls_codec::Error::EndOfStream)?;
+    if batch.is_empty() {
+        return Ok(false);
+    }
+
+    while !batch.is_empty() {
+        let _version = u16::tls_deserialize(&mut batch)?;
+        let wire_format = u16::tls_deserialize(&mut batch)?;
+        if wire_format != WIRE_FORMAT_PRIVATE_MESSAGE {
+            return Ok(false);
+        }
+
+        let _group_id = VLBytes::tls_deserialize(&mut batch)?;
+        let _epoch = u64::tls_deserialize(&mut batch)?;
+        let _content_type = u8::tls_deserialize(&mut batch)?;
+        let authenticated_data = VLBytes::tls_deserialize(&mut batch)?;
+        if authenticated_data.as_slice() != EPHEMERAL_AAD {
+            return Ok(false);
+        }
+
+        let _encrypted_sender_data = VLBytes::tls_deserialize(&mut batch)?;
+        let _ciphertext = VLBytes::tls_deserialize(&mut batch)?;
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use meal_core::{Content, OutgoingMessage, v2::Client};
+    use time::OffsetDateTime;
+
+    use super::*;
+
+    fn client_with_group() -> (Client, String) {
+        let mut client = Client::new().unwrap();
+        let group_id = client.create_group(None).unwrap();
+        (client, group_id)
+    }
+
+    #[test]
+    fn typing_indicator_is_ephemeral() {
+        let (mut client, group_id) = client_with_group();
+        let payload = client.send_typing(&group_id, true).unwrap();
+        assert!(is_ephemeral(&payload));
+    }
+
+    #[test]
+    fn text_message_is_not_ephemeral() {
+        let (mut client, group_id) = client_with_group();
+        let message = OutgoingMessage {
+            sent_at: OffsetDateTime::now_utc(),
+            content: Content::Text {
+                text: "Hello".to_owned(),
+            },
+        };
+        let sent = client.send(&group_id, message).unwrap();
+        assert!(!is_ephemeral(&sent.payload));
+    }
+
+    #[test]
+    fn unreadable_payload_is_not_ephemeral() {
+        assert!(!is_ephemeral(&[]));
+        assert!(!is_ephemeral(&[0, 0]));
+        let (mut client, group_id) = client_with_group();
+        let payload = client.send_typing(&group_id, true).unwrap();
+        assert!(!is_ephemeral(&payload[..payload.len() - 1]));
+    }
+}
@@ -27,6 +27,7 @@ use tracing::Level;
 use crate::actor::switchboard::{self, SendMessageError};
 
 mod actor;
+mod ephemeral;
 mod extractor;
 mod telemetry;
 
@@ -132,8 +133,9 @@ async fn create_message(
     //TODO think about not leaking if they exist or not
     //TODO think about leaking data through timings
     let data = bytes.as_ref();
+    let is_ephemeral = ephemeral::is_ephemeral(data);
     let data = Arc::from(data);
-    match state.switchboard.send_message(to, data).await {
+    match state.switchboard.send_message(to, data, is_ephemeral).await {
         Ok(()) => StatusCode::CREATED,
         Err(SendMessageError::ClientNotFound) => StatusCode::NOT_FOUND,
         Err(SendMessageError::Closed) => {